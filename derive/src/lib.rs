@@ -0,0 +1,164 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Derive macro for the `Commands` trait of proptest-stateful. Use it through the
+//! `derive` feature of proptest-stateful rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Expr, Field, Fields,
+    Result, Type,
+};
+
+/// Derive `Commands<Model>` for an enum of commands, generating one strategy per
+/// variant. The model is given with `#[commands(model = Model)]` on the enum; without
+/// it, the strategies are generated for any model. The following attributes can be
+/// used on the variants and their fields, with expressions which may refer to the
+/// current state of the model through the `model` binding:
+///
+/// * `#[weight(expr)]` sets the weight of the variant (default: 1). Variants with a
+///   weight of 0 are not generated.
+/// * `#[enabled_if(expr)]` only generates the variant when the condition holds.
+/// * `#[strategy(expr)]` sets the strategy of a field (default: `any::<T>()`).
+#[proc_macro_derive(Commands, attributes(commands, weight, enabled_if, strategy))]
+pub fn derive_commands(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let data = match input.data {
+        Data::Enum(ref data) => data,
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "Commands can only be derived for enums",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let mut strategies = Vec::with_capacity(data.variants.len());
+    for variant in &data.variants {
+        let weight = attribute(&variant.attrs, "weight")?.unwrap_or_else(|| parse_quote!(1));
+        let enabled =
+            attribute(&variant.attrs, "enabled_if")?.unwrap_or_else(|| parse_quote!(true));
+        let ident = &variant.ident;
+        let strategy = match variant.fields {
+            Fields::Unit => quote! {
+                ::proptest_stateful::__proptest::strategy::Just(#name::#ident)
+            },
+            Fields::Named(ref fields) => {
+                let bindings = fields
+                    .named
+                    .iter()
+                    .map(|field| field.ident.clone().unwrap())
+                    .collect::<Vec<_>>();
+                let fields = field_strategies(fields.named.iter())?;
+                quote! {
+                    ::proptest_stateful::__proptest::strategy::Strategy::prop_map(
+                        (#(#fields,)*),
+                        |(#(#bindings,)*)| #name::#ident { #(#bindings),* },
+                    )
+                }
+            }
+            Fields::Unnamed(ref fields) => {
+                let bindings = (0..fields.unnamed.len())
+                    .map(|index| format_ident!("field{}", index))
+                    .collect::<Vec<_>>();
+                let fields = field_strategies(fields.unnamed.iter())?;
+                quote! {
+                    ::proptest_stateful::__proptest::strategy::Strategy::prop_map(
+                        (#(#fields,)*),
+                        |(#(#bindings,)*)| #name::#ident(#(#bindings),*),
+                    )
+                }
+            }
+        };
+        strategies.push(quote! {
+            let weight: usize = #weight;
+            if weight > 0 && #enabled {
+                strategies.push((weight, ::proptest_stateful::__proptest::strategy::Strategy::boxed(#strategy)));
+            }
+        });
+    }
+
+    let mut generics = input.generics.clone();
+    let model = match model_type(&input.attrs)? {
+        Some(model) => model,
+        None => {
+            generics.params.push(parse_quote!(M));
+            parse_quote!(M)
+        }
+    };
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let model_binding = Ident::new("model", Span::call_site());
+
+    Ok(quote! {
+        impl #impl_generics ::proptest_stateful::Commands<#model> for #name #ty_generics
+            #where_clause
+        {
+            #[allow(unused_variables)]
+            fn strategies(
+                #model_binding: &#model,
+            ) -> ::std::vec::Vec<(usize, ::proptest_stateful::__proptest::strategy::BoxedStrategy<Self>)> {
+                let mut strategies = ::std::vec::Vec::new();
+                #({ #strategies })*
+                strategies
+            }
+        }
+    })
+}
+
+/// Returns the model type given with `#[commands(model = Model)]`, if any
+fn model_type(attrs: &[Attribute]) -> Result<Option<Type>> {
+    let mut model = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("commands")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("model") {
+                model = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported commands attribute"))
+            }
+        })?;
+    }
+    Ok(model)
+}
+
+/// Returns the expression given to the attribute with the provided name, if any
+fn attribute(attrs: &[Attribute], name: &str) -> Result<Option<Expr>> {
+    attrs
+        .iter()
+        .find(|attr| attr.path().is_ident(name))
+        .map(|attr| attr.parse_args())
+        .transpose()
+}
+
+/// Returns the strategy of each field, given by its `#[strategy(...)]` attribute or
+/// derived from the type of the field
+fn field_strategies<'a, I>(fields: I) -> Result<Vec<TokenStream2>>
+where
+    I: Iterator<Item = &'a Field>,
+{
+    fields
+        .map(|field| {
+            Ok(match attribute(&field.attrs, "strategy")? {
+                Some(strategy) => quote!(#strategy),
+                None => {
+                    let ty = &field.ty;
+                    quote!(::proptest_stateful::__proptest::arbitrary::any::<#ty>())
+                }
+            })
+        })
+        .collect()
+}
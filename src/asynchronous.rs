@@ -0,0 +1,379 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, JoinHandle, Thread},
+    time::{Duration, Instant},
+};
+
+use proptest::test_runner::TestRunner;
+
+use crate::{
+    command_sequence, report_failure, with_cleanup, CommandSequence, Config, Error, Result,
+    StateMachine, Steps, TestFailure,
+};
+
+/// Boxed future returned by the methods of asynchronous systems-under-test
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// This trait represents the interface to a system-under-test with an asynchronous
+/// interface. The two type parameters, `C` and `R`, are the types encoding the commands
+/// the system can receive, respectively the responses given by the system to various
+/// commands.
+pub trait AsyncSystemUnderTest<C, R> {
+    /// The method takes a reference to a system command and returns a future which
+    /// applies the command to the system and resolves to the corresponding response.
+    fn run<'a>(&'a mut self, cmd: &'a C) -> BoxFuture<'a, Result<R>>;
+
+    /// Returns a future which releases the resources held by the system (temporary
+    /// files, child processes, etc.). The future is awaited once the command sequence
+    /// has been run, whether it succeeded or not.
+    fn cleanup(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Executor used to drive the futures of an asynchronous system-under-test to completion.
+/// Implement it for a handle of the runtime the system is written for (for example, by
+/// forwarding to the `block_on` method of the runtime).
+pub trait Executor {
+    /// Run the future to completion on the current thread, returning its output
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+/// Minimal executor which polls the future on the current thread, parking the thread
+/// until the future is woken up. It is sufficient for systems which do not depend on
+/// the services of a specific runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockingExecutor;
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Executor for BlockingExecutor {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    expired: bool,
+    waker: Option<Waker>,
+}
+
+struct Deadline {
+    instant: Instant,
+    state: Arc<Mutex<TimerState>>,
+}
+
+#[derive(Default)]
+struct Deadlines {
+    pending: Vec<Deadline>,
+    stopped: bool,
+}
+
+/// Expires the timeouts of the commands on a single helper thread, so that they do not
+/// depend on the executor driving the futures. The thread lives as long as the timer,
+/// which is shared by all the command sequences of a test run.
+struct Timer {
+    deadlines: Arc<(Mutex<Deadlines>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Timer {
+    fn new() -> Self {
+        let deadlines = Arc::new((Mutex::new(Deadlines::default()), Condvar::new()));
+        let shared = deadlines.clone();
+        let thread = thread::spawn(move || {
+            let (ref deadlines, ref condvar) = *shared;
+            let mut deadlines = deadlines.lock().unwrap();
+            while !deadlines.stopped {
+                let now = Instant::now();
+                deadlines.pending.retain(|deadline| {
+                    if deadline.instant > now {
+                        return true;
+                    }
+                    let mut state = deadline.state.lock().unwrap();
+                    state.expired = true;
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                    false
+                });
+                deadlines = match deadlines.pending.iter().map(|d| d.instant).min() {
+                    Some(next) => condvar.wait_timeout(deadlines, next - now).unwrap().0,
+                    None => condvar.wait(deadlines).unwrap(),
+                };
+            }
+        });
+        Timer {
+            deadlines,
+            thread: Some(thread),
+        }
+    }
+
+    fn start(&self, duration: Duration) -> Arc<Mutex<TimerState>> {
+        let state = Arc::new(Mutex::new(TimerState::default()));
+        let (ref deadlines, ref condvar) = *self.deadlines;
+        deadlines.lock().unwrap().pending.push(Deadline {
+            instant: Instant::now() + duration,
+            state: state.clone(),
+        });
+        condvar.notify_one();
+        state
+    }
+
+    fn cancel(&self, state: &Arc<Mutex<TimerState>>) {
+        let (ref deadlines, ref condvar) = *self.deadlines;
+        let mut deadlines = deadlines.lock().unwrap();
+        deadlines
+            .pending
+            .retain(|deadline| !Arc::ptr_eq(&deadline.state, state));
+        condvar.notify_one();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let (ref deadlines, ref condvar) = *self.deadlines;
+        deadlines.lock().unwrap().stopped = true;
+        condvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Wraps a future, resolving to `None` if the future does not complete within the
+/// given duration
+struct Timeout<'t, F> {
+    future: F,
+    timer: &'t Timer,
+    state: Arc<Mutex<TimerState>>,
+}
+
+impl<'t, F> Timeout<'t, F> {
+    fn new(future: F, duration: Duration, timer: &'t Timer) -> Self {
+        Timeout {
+            future,
+            timer,
+            state: timer.start(duration),
+        }
+    }
+}
+
+impl<F> Drop for Timeout<'_, F> {
+    fn drop(&mut self) {
+        self.timer.cancel(&self.state);
+    }
+}
+
+impl<F> Future for Timeout<'_, F>
+where
+    F: Future + Unpin,
+{
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.expired {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<SM> CommandSequence<SM>
+where
+    SM: StateMachine + Debug,
+{
+    /// Run the command sequence against an asynchronous system-under-test. When a
+    /// timeout is given, each command which does not complete in time fails with
+    /// `Error::Timeout`. The system-under-test is cleaned up afterwards, as done by
+    /// `run` for synchronous systems.
+    pub async fn run_async(
+        &mut self,
+        system_under_test: &mut Box<dyn AsyncSystemUnderTest<SM::Command, SM::CommandResult>>,
+        command_timeout: Option<Duration>,
+    ) -> Result<()> {
+        match command_timeout {
+            Some(timeout) => {
+                let timer = Timer::new();
+                self.run_timed(system_under_test, Some((&timer, timeout)))
+                    .await
+            }
+            None => self.run_timed(system_under_test, None).await,
+        }
+    }
+
+    async fn run_timed(
+        &mut self,
+        system_under_test: &mut Box<dyn AsyncSystemUnderTest<SM::Command, SM::CommandResult>>,
+        command_timeout: Option<(&Timer, Duration)>,
+    ) -> Result<()> {
+        let result = self.apply_timed(system_under_test, command_timeout).await;
+        with_cleanup(result, system_under_test.cleanup().await)
+    }
+
+    async fn apply_timed(
+        &mut self,
+        system_under_test: &mut Box<dyn AsyncSystemUnderTest<SM::Command, SM::CommandResult>>,
+        command_timeout: Option<(&Timer, Duration)>,
+    ) -> Result<()> {
+        let mut steps = Steps::new(&mut self.state_machine);
+        for (step, (cmd, var)) in self.commands.iter().zip(&self.variables).enumerate() {
+            let concrete_cmd = steps.resolve(cmd)?;
+            let result = match command_timeout {
+                Some((timer, timeout)) => {
+                    Timeout::new(system_under_test.run(&concrete_cmd), timeout, timer)
+                        .await
+                        .ok_or_else(|| Error::timeout(format!("{:?}", cmd), timeout))??
+                }
+                None => system_under_test.run(&concrete_cmd).await?,
+            };
+            steps.check(step, *var, cmd, result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run a set of tests for the provided asynchronous system-under-test, using the given
+/// state machine model and configuration parameters. The command sequences are driven
+/// to completion by the provided executor.
+pub fn run_async<SM, SUTF, E>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+    executor: E,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug,
+    SUTF: Fn() -> Box<dyn AsyncSystemUnderTest<SM::Command, SM::CommandResult>>,
+    E: Executor,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+    let timer = config
+        .command_timeout
+        .map(|timeout| (Timer::new(), timeout));
+    let command_timeout = timer.as_ref().map(|(timer, timeout)| (timer, *timeout));
+
+    let strategy = command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let mut sys = system_under_test_factory();
+        executor.block_on(commands.run_timed(&mut sys, command_timeout))?;
+        Ok(())
+    });
+    report_failure(result, strategy.generation_error.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, future::pending, rc::Rc, time::Duration};
+
+    use crate::test_models::{CounterCommand, CounterModel};
+    use crate::{
+        run_async, AsyncSystemUnderTest, BlockingExecutor, BoxFuture, CommandSequence, Config,
+        Error, Executor, Result, Var,
+    };
+
+    // Never completes the increments of a counter which reached `hang_at`, and
+    // counts its cleanups
+    struct AsyncCounter {
+        value: usize,
+        hang_at: usize,
+        cleanups: Rc<Cell<usize>>,
+    }
+
+    impl AsyncSystemUnderTest<CounterCommand, usize> for AsyncCounter {
+        fn run<'a>(&'a mut self, _cmd: &'a CounterCommand) -> BoxFuture<'a, Result<usize>> {
+            Box::pin(async move {
+                if self.value == self.hang_at {
+                    pending::<()>().await;
+                }
+                self.value += 1;
+                Ok(self.value)
+            })
+        }
+
+        fn cleanup(&mut self) -> BoxFuture<'_, Result<()>> {
+            self.cleanups.set(self.cleanups.get() + 1);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn async_system_is_driven_by_executor() {
+        let mut config = Config {
+            command_timeout: Some(Duration::from_secs(10)),
+            ..Config::default()
+        };
+        config.proptest.cases = 16;
+        let cleanups = Rc::new(Cell::new(0));
+        let result = run_async(
+            config,
+            CounterModel { value: 0 },
+            || {
+                Box::new(AsyncCounter {
+                    value: 0,
+                    hang_at: usize::MAX,
+                    cleanups: cleanups.clone(),
+                })
+            },
+            BlockingExecutor,
+        );
+        assert!(result.is_ok());
+        assert_eq!(cleanups.get(), 16);
+    }
+
+    #[test]
+    fn command_timeout_is_reported() {
+        let cleanups = Rc::new(Cell::new(0));
+        let mut commands = CommandSequence::new(
+            CounterModel { value: 0 },
+            vec![
+                (Var(0), CounterCommand::Increment),
+                (Var(1), CounterCommand::Increment),
+            ],
+        );
+        let mut sys: Box<dyn AsyncSystemUnderTest<CounterCommand, usize>> =
+            Box::new(AsyncCounter {
+                value: 0,
+                hang_at: 1,
+                cleanups: cleanups.clone(),
+            });
+        let result = BlockingExecutor
+            .block_on(commands.run_async(&mut sys, Some(Duration::from_millis(10))));
+        match result {
+            Err(Error::Timeout { command, .. }) => assert_eq!(command, "Increment"),
+            _ => panic!("Command should have timed out"),
+        }
+        assert_eq!(cleanups.get(), 1);
+    }
+}
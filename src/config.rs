@@ -5,8 +5,37 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::{path::PathBuf, time::Duration};
+
 use proptest::prelude::ProptestConfig;
 
+/// Algorithm used to delete commands from a failing command sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shrinker {
+    /// Attempt to delete each command in turn, from the first to the last one,
+    /// repeating until no command can be deleted
+    Sequential,
+    /// Attempt to delete halves, quarters, etc. of the command sequence before
+    /// falling back to single commands, repeating until no command can be deleted
+    DeltaDebugging,
+}
+
+/// Distribution of the lengths of the generated command sequences over the course of
+/// a test run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeDistribution {
+    /// Every length between `min_sequence_size` and `max_sequence_size` is equally
+    /// likely, independently of the test case
+    Uniform,
+    /// The maximum length grows linearly from `min_sequence_size`, reaching
+    /// `max_sequence_size` with the last test case
+    Linear,
+    /// The maximum length grows geometrically from `min_sequence_size`, so that
+    /// most test cases use short command sequences, reaching `max_sequence_size`
+    /// with the last test case
+    Geometric,
+}
+
 /// Configuration object for a test run
 pub struct Config {
     /// Minimum number of commands in the generated command sequence
@@ -17,10 +46,76 @@ pub struct Config {
     /// (default: 100)
     pub max_sequence_size: usize,
 
+    /// Distribution of the lengths of the generated command sequences
+    /// (default: SizeDistribution::Uniform)
+    pub size_distribution: SizeDistribution,
+
+    /// Initial temperature of the simulated annealing performed by
+    /// `run_targeted`, relative to the differences between utility values.
+    /// It decreases linearly to 0 over the test run. With a temperature of 0,
+    /// the search is a hill climbing (default: 1.0)
+    pub targeted_temperature: f64,
+
+    /// Number of consecutive samples without a new command after which
+    /// `run_exhaustive` considers the domain of a command strategy to be
+    /// exhausted (default: 100)
+    pub enumeration_samples: usize,
+
+    /// Maximum number of samples drawn from the command strategies to enumerate
+    /// the commands of a model state in `run_exhaustive`. The test run is aborted
+    /// when the strategies still yield new commands after that many samples,
+    /// since their domain is unlikely to be finite (default: 10000)
+    pub max_enumeration_samples: usize,
+
+    /// End the generated command sequence early when the model offers no
+    /// command with a positive weight, instead of failing the test run with
+    /// `TestFailure::Generation(Error::NoCommand { .. })` (default: false)
+    pub stop_without_commands: bool,
+
     /// Once the minimal command sequence has been found, also attempt
-    /// to simplify the individual commands (default: false)
+    /// to simplify the individual commands. Deletion and simplification
+    /// passes alternate until neither makes progress, within the limit of
+    /// `proptest.max_shrink_iters` (default: false)
     pub shrink_commands: bool,
 
+    /// Algorithm used to delete commands from a failing command sequence
+    /// (default: Shrinker::Sequential)
+    pub shrinker: Shrinker,
+
+    /// Minimum number of commands kept when shrinking a failing command
+    /// sequence. Set it to `min_sequence_size` to honour the minimum size
+    /// of the generated sequences, or to 0 to detect failures which happen
+    /// before any command is run, reported as `TestFailure::BeforeAnyCommand`
+    /// (default: 1)
+    pub min_shrink_size: usize,
+
+    /// Print the statistics of the command sequences once all test cases
+    /// have passed (default: false)
+    pub print_statistics: bool,
+
+    /// Maximum duration of each command run by `run_async`, after which the
+    /// command fails with a timeout error (default: None)
+    pub command_timeout: Option<Duration>,
+
+    /// Number of concurrent branches in a parallel command sequence
+    /// (default: 2)
+    pub parallel_branches: usize,
+
+    /// Maximum number of commands in each concurrent branch of a parallel
+    /// command sequence (default: 5)
+    pub max_branch_size: usize,
+
+    /// File in which `run` stores the minimal failing command sequences, serialized
+    /// as JSON with the `serde` feature. The stored sequences are replayed at the
+    /// beginning of every subsequent run. When not set, the file is placed next to
+    /// `proptest.source_file`, with the `sequences` extension (default: None)
+    pub regressions_file: Option<PathBuf>,
+
+    /// When the reset of the system-under-test reused by `run_reused` fails, clean
+    /// it up and build a new one from the factory instead of failing the test case
+    /// (default: true)
+    pub rebuild_on_failed_reset: bool,
+
     /// Parameters for the underlying proptest library
     pub proptest: ProptestConfig,
 }
@@ -30,8 +125,21 @@ impl Default for Config {
         Config {
             min_sequence_size: 1,
             max_sequence_size: 100,
+            size_distribution: SizeDistribution::Uniform,
+            targeted_temperature: 1.0,
+            enumeration_samples: 100,
+            max_enumeration_samples: 10000,
+            stop_without_commands: false,
             shrink_commands: false,
+            shrinker: Shrinker::Sequential,
+            min_shrink_size: 1,
+            print_statistics: false,
+            command_timeout: None,
+            parallel_branches: 2,
+            max_branch_size: 5,
+            regressions_file: None,
+            rebuild_on_failed_reset: true,
             proptest: ProptestConfig::default(),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,141 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt::Debug;
+
+use proptest::test_runner::TestRunner;
+
+use crate::{
+    command_sequence, report_failure, with_cleanup, CommandSequence, Config, Error, Result,
+    StateMachine, Steps, SystemUnderTest, TestFailure,
+};
+
+impl<SM> CommandSequence<SM>
+where
+    SM: StateMachine + Debug,
+    SM::CommandResult: PartialEq,
+{
+    /// Run the command sequence against both a reference implementation and the
+    /// system-under-test, failing with `Error::Divergence` at the first command whose
+    /// results differ. The model only tracks the state needed to resolve symbolic
+    /// variables: its postconditions and invariants are not checked. Both systems are
+    /// cleaned up afterwards.
+    pub fn run_differential(
+        &mut self,
+        reference: &mut Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+        system_under_test: &mut Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+    ) -> Result<()> {
+        let result = self.compare_steps(&mut **reference, &mut **system_under_test);
+        let cleanup = reference.cleanup();
+        let result = with_cleanup(result, system_under_test.cleanup());
+        with_cleanup(result, cleanup)
+    }
+
+    fn compare_steps(
+        &mut self,
+        reference: &mut dyn SystemUnderTest<SM::Command, SM::CommandResult>,
+        system_under_test: &mut dyn SystemUnderTest<SM::Command, SM::CommandResult>,
+    ) -> Result<()> {
+        let mut steps = Steps::new(&mut self.state_machine);
+        for (step, (cmd, var)) in self.commands.iter().zip(&self.variables).enumerate() {
+            let concrete_cmd = steps.resolve(cmd)?;
+            let expected = reference.run(&concrete_cmd)?;
+            let actual = system_under_test.run(&concrete_cmd)?;
+            if expected != actual {
+                return Err(Error::divergence(
+                    step,
+                    format!("{:?}", cmd),
+                    format!("{:?}", expected),
+                    format!("{:?}", actual),
+                ));
+            }
+            steps.advance(*var, cmd, actual);
+        }
+        Ok(())
+    }
+}
+
+/// Run a set of tests comparing the provided system-under-test with a reference
+/// implementation, such as an older version of the system. The model is only used to
+/// generate the command sequences, which are run against both systems; the test fails
+/// at the first command for which the two systems return different results.
+pub fn run_differential<SM, RF, SUTF>(
+    config: Config,
+    state_machine: SM,
+    reference_factory: RF,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug,
+    SM::CommandResult: PartialEq,
+    RF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+
+    let strategy = command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let mut reference = reference_factory();
+        let mut sys = system_under_test_factory();
+        commands.run_differential(&mut reference, &mut sys)?;
+        Ok(())
+    });
+    report_failure(result, strategy.generation_error.take())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::test_models::{StackCommand, StackModel, StackSystem};
+    use crate::{run_differential, Config, Result, SystemUnderTest, TestFailure};
+
+    // Does not remove the top of a stack holding two elements
+    struct StickyStack {
+        depth: usize,
+    }
+
+    impl SystemUnderTest<StackCommand, usize> for StickyStack {
+        fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+            match *cmd {
+                StackCommand::Push => self.depth += 1,
+                StackCommand::Pop if self.depth == 2 => {}
+                StackCommand::Pop => self.depth -= 1,
+            }
+            Ok(self.depth)
+        }
+    }
+
+    fn stack() -> Box<dyn SystemUnderTest<StackCommand, usize>> {
+        Box::new(StackSystem {
+            depth: 0,
+            capacity: usize::MAX,
+        })
+    }
+
+    #[test]
+    fn identical_systems_do_not_diverge() {
+        let result = run_differential(Config::default(), StackModel { depth: 0 }, stack, stack);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn first_divergent_step_is_reported() {
+        let result = run_differential(Config::default(), StackModel { depth: 0 }, stack, || {
+            Box::new(StickyStack { depth: 0 })
+        });
+        match result {
+            Err(TestFailure::Fail(reason, seq)) => {
+                assert_eq!(seq.commands.len(), 3);
+                assert_eq!(seq.commands[2], StackCommand::Pop);
+                assert!(reason
+                    .message()
+                    .starts_with("System diverges from the reference implementation at step 2"));
+            }
+            _ => panic!("Test should have failed"),
+        }
+    }
+}
@@ -5,6 +5,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::time::Duration;
+
+use proptest::test_runner::{Reason, TestError};
+
+use crate::symbolic::Var;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// The errors which can be produced by the library
@@ -14,12 +20,50 @@ pub enum Error {
     SystemUnderTest {
         source: Box<dyn std::error::Error + 'static>,
     },
+    /// Command did not complete within the configured timeout
+    Timeout { command: String, timeout: Duration },
     /// Model state machine postcondition does not hold
     Postcondition {
         command: String,
         expected: String,
         actual: String,
     },
+    /// Observation of the system-under-test differs from the observation of the model
+    Observation {
+        step: usize,
+        command: String,
+        expected: String,
+        actual: String,
+    },
+    /// Result of the system-under-test differs from the result of the reference
+    /// implementation
+    Divergence {
+        step: usize,
+        command: String,
+        expected: String,
+        actual: String,
+    },
+    /// Model state machine invariant does not hold
+    Invariant {
+        step: usize,
+        state: String,
+        message: String,
+    },
+    /// Model state machine offers no command with a positive weight while generating
+    /// a command sequence. The test run fails with `TestFailure::Generation`.
+    NoCommand { step: usize, state: String },
+    /// Symbolic variable referenced by a command is not bound to a value
+    UnboundVariable { var: Var },
+    /// No linearization of the concurrent history satisfies the postconditions
+    Linearizability { history: String },
+    /// Cleanup of the system-under-test failed after the command sequence was run.
+    /// When the command sequence failed as well, its error is kept in `original`.
+    Cleanup {
+        source: Box<Error>,
+        original: Option<Box<Error>>,
+    },
+    /// Reset of a reused system-under-test failed before the command sequence was run
+    Reset { source: Box<Error> },
 }
 
 impl Error {
@@ -39,13 +83,96 @@ impl Error {
             actual: actual.as_ref().to_string(),
         }
     }
+
+    pub fn timeout<T: AsRef<str>>(command: T, timeout: Duration) -> Error {
+        Self::Timeout {
+            command: command.as_ref().to_string(),
+            timeout,
+        }
+    }
+
+    pub fn observation<T: AsRef<str>>(step: usize, command: T, expected: T, actual: T) -> Error {
+        Self::Observation {
+            step,
+            command: command.as_ref().to_string(),
+            expected: expected.as_ref().to_string(),
+            actual: actual.as_ref().to_string(),
+        }
+    }
+
+    pub fn divergence<T: AsRef<str>>(step: usize, command: T, expected: T, actual: T) -> Error {
+        Self::Divergence {
+            step,
+            command: command.as_ref().to_string(),
+            expected: expected.as_ref().to_string(),
+            actual: actual.as_ref().to_string(),
+        }
+    }
+
+    /// Construct the error returned by a failing model invariant. The step index and
+    /// the model state are filled in by the library.
+    pub fn invariant<T: AsRef<str>>(message: T) -> Error {
+        Self::Invariant {
+            step: 0,
+            state: String::new(),
+            message: message.as_ref().to_string(),
+        }
+    }
+
+    pub fn no_command<T: AsRef<str>>(step: usize, state: T) -> Error {
+        Self::NoCommand {
+            step,
+            state: state.as_ref().to_string(),
+        }
+    }
+
+    pub fn unbound_variable(var: Var) -> Error {
+        Self::UnboundVariable { var }
+    }
+
+    pub fn linearizability<T: AsRef<str>>(history: T) -> Error {
+        Self::Linearizability {
+            history: history.as_ref().to_string(),
+        }
+    }
+
+    pub fn cleanup(source: Error) -> Error {
+        Self::Cleanup {
+            source: Box::new(source),
+            original: None,
+        }
+    }
+
+    /// Construct the error returned when both the command sequence, with the error
+    /// `original`, and the cleanup of the system-under-test failed
+    pub fn cleanup_after(original: Error, source: Error) -> Error {
+        Self::Cleanup {
+            source: Box::new(source),
+            original: Some(Box::new(original)),
+        }
+    }
+
+    pub fn reset(source: Error) -> Error {
+        Self::Reset {
+            source: Box::new(source),
+        }
+    }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             Error::SystemUnderTest { ref source } => Some(&**source),
+            Error::Timeout { .. } => None,
             Error::Postcondition { .. } => None,
+            Error::Observation { .. } => None,
+            Error::Divergence { .. } => None,
+            Error::Invariant { .. } => None,
+            Error::NoCommand { .. } => None,
+            Error::UnboundVariable { .. } => None,
+            Error::Linearizability { .. } => None,
+            Error::Cleanup { ref source, .. } => Some(&**source),
+            Error::Reset { ref source } => Some(&**source),
         }
     }
 }
@@ -54,6 +181,16 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::SystemUnderTest { ref source } => source.fmt(f),
+            Error::Timeout {
+                ref command,
+                ref timeout,
+            } => {
+                write!(
+                    f,
+                    "Command did not complete within {:?}. Command: {}",
+                    timeout, command
+                )
+            }
             Error::Postcondition {
                 ref command,
                 ref expected,
@@ -65,6 +202,141 @@ impl std::fmt::Display for Error {
                     command, expected, actual
                 )
             }
+            Error::Observation {
+                ref step,
+                ref command,
+                ref expected,
+                ref actual,
+            } => {
+                write!(
+                    f,
+                    "Observation does not match the model after step {}. Command: {}. Expected observation: {}. Actual observation: {}",
+                    step, command, expected, actual
+                )
+            }
+            Error::Divergence {
+                ref step,
+                ref command,
+                ref expected,
+                ref actual,
+            } => {
+                write!(
+                    f,
+                    "System diverges from the reference implementation at step {}. Command: {}. Reference result: {}. Actual result: {}",
+                    step, command, expected, actual
+                )
+            }
+            Error::Invariant {
+                ref step,
+                ref state,
+                ref message,
+            } => {
+                write!(
+                    f,
+                    "Invariant does not hold after step {}. State: {}. Reason: {}",
+                    step, state, message
+                )
+            }
+            Error::NoCommand {
+                ref step,
+                ref state,
+            } => {
+                write!(
+                    f,
+                    "No command with a positive weight is available at step {}. State: {}",
+                    step, state
+                )
+            }
+            Error::UnboundVariable { ref var } => {
+                write!(f, "Symbolic variable {} is not bound to a value", var)
+            }
+            Error::Linearizability { ref history } => {
+                write!(
+                    f,
+                    "No linearization of the concurrent history satisfies the postconditions. History: {}",
+                    history
+                )
+            }
+            Error::Cleanup {
+                ref source,
+                original: None,
+            } => {
+                write!(f, "Cleanup of the system-under-test failed: {}", source)
+            }
+            Error::Cleanup {
+                ref source,
+                original: Some(ref original),
+            } => {
+                write!(
+                    f,
+                    "{} (cleanup of the system-under-test failed as well: {})",
+                    original, source
+                )
+            }
+            Error::Reset { ref source } => {
+                write!(f, "Reset of the system-under-test failed: {}", source)
+            }
+        }
+    }
+}
+
+/// The failures of a test run. Besides the failures reported by the proptest runner,
+/// whose reasons only hold the message of the errors, the generation of the command
+/// sequences can fail with an error of the library, and the test can fail before any
+/// command is run.
+#[derive(Debug)]
+pub enum TestFailure<T> {
+    /// The test run was aborted, for example when too many command sequences were
+    /// rejected
+    Abort(Reason),
+    /// A command sequence failed; holds the reason of the failure and the minimal
+    /// failing case
+    Fail(Reason, T),
+    /// The minimal failing case holds no command: the test fails before any command
+    /// is run, for example while building or cleaning up the system-under-test, and
+    /// the model is not involved. Holds the reason of the failure.
+    BeforeAnyCommand(Reason),
+    /// A command sequence could not be generated from the model
+    Generation(Error),
+}
+
+impl<T> From<TestError<T>> for TestFailure<T> {
+    fn from(error: TestError<T>) -> Self {
+        match error {
+            TestError::Abort(reason) => Self::Abort(reason),
+            TestError::Fail(reason, value) => Self::Fail(reason, value),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for TestFailure<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            TestFailure::Abort(..) => None,
+            TestFailure::Fail(..) => None,
+            TestFailure::BeforeAnyCommand(..) => None,
+            TestFailure::Generation(ref source) => Some(source),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Display for TestFailure<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TestFailure::Abort(ref reason) => write!(f, "Test aborted: {}", reason),
+            TestFailure::Fail(ref reason, ref value) => {
+                write!(
+                    f,
+                    "Test failed: {}.\nminimal failing input: {:#?}",
+                    reason, value
+                )
+            }
+            TestFailure::BeforeAnyCommand(ref reason) => {
+                write!(f, "Test failed before any command was run: {}", reason)
+            }
+            TestFailure::Generation(ref source) => {
+                write!(f, "Command sequence could not be generated: {}", source)
+            }
         }
     }
 }
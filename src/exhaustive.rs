@@ -0,0 +1,331 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
+
+use proptest::{
+    strategy::{Strategy, ValueTree},
+    test_runner::{Reason, TestError, TestRunner},
+};
+
+use crate::{
+    applicable, report_failure, CommandSequence, Config, StateMachine, SystemUnderTest,
+    TestFailure, Var,
+};
+
+/// Returns the commands applicable to the current state of the model, either from the
+/// enumerator of the model or by sampling each command strategy until `samples`
+/// consecutive samples yield no new command. Commands are told apart by their `Debug`
+/// representation. Sampling fails once `max_samples` samples were drawn in total,
+/// since the domain of the strategies is then unlikely to be finite.
+fn enumerate<SM>(
+    runner: &mut TestRunner,
+    state_machine: &SM,
+    bound: &HashSet<Var>,
+    samples: usize,
+    max_samples: usize,
+) -> std::result::Result<Vec<SM::Command>, Reason>
+where
+    SM: StateMachine + Debug,
+{
+    let commands = match state_machine.enumerate_commands() {
+        Some(commands) => commands,
+        None => sample_commands(runner, state_machine, samples, max_samples)?,
+    };
+    Ok(commands
+        .into_iter()
+        .filter(|cmd| applicable(state_machine, bound, cmd))
+        .collect())
+}
+
+fn sample_commands<SM>(
+    runner: &mut TestRunner,
+    state_machine: &SM,
+    samples: usize,
+    max_samples: usize,
+) -> std::result::Result<Vec<SM::Command>, Reason>
+where
+    SM: StateMachine + Debug,
+{
+    let mut seen = HashSet::new();
+    let mut commands = Vec::new();
+    let mut total = 0;
+    for (weight, strategy) in state_machine.commands() {
+        if weight == 0 {
+            continue;
+        }
+        let mut misses = 0;
+        while misses < samples {
+            if total == max_samples {
+                return Err(format!(
+                    "Command strategies still yield new commands after {} samples in state {:?}. \
+                     Their domain does not seem to be finite: implement \
+                     StateMachine::enumerate_commands to enumerate the commands",
+                    max_samples, state_machine
+                )
+                .into());
+            }
+            total += 1;
+            let cmd = match strategy.new_tree(runner) {
+                Ok(tree) => tree.current(),
+                Err(_) => break,
+            };
+            if seen.insert(format!("{:?}", cmd)) {
+                commands.push(cmd);
+                misses = 0;
+            } else {
+                misses += 1;
+            }
+        }
+    }
+    Ok(commands)
+}
+
+/// Depth-first search over the command sequences of bounded length
+struct Search<'a, SM, SUTF>
+where
+    SM: StateMachine,
+{
+    state_machine: SM,
+    system_under_test_factory: &'a SUTF,
+    runner: TestRunner,
+    samples: usize,
+    max_samples: usize,
+    // Maximum length of the command sequences which remain to be explored
+    max_size: usize,
+    // Shallowest depth at which each model state was reached, keyed by its `Debug`
+    // representation
+    visited: HashMap<String, usize>,
+    failure: Option<(String, CommandSequence<SM>)>,
+}
+
+impl<SM, SUTF> Search<'_, SM, SUTF>
+where
+    SM: StateMachine + Clone + Debug,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    /// Run every extension of `commands` by one command, then explore the extensions
+    /// which lead to a model state not reached before at the same or a lower depth.
+    /// Once a failing sequence is found, only shorter sequences are explored.
+    fn explore(
+        &mut self,
+        state_machine: &SM,
+        commands: &mut Vec<(Var, SM::Command)>,
+    ) -> std::result::Result<(), Reason> {
+        let depth = commands.len();
+        let bound = (0..depth).map(Var).collect();
+        let candidates = enumerate(
+            &mut self.runner,
+            state_machine,
+            &bound,
+            self.samples,
+            self.max_samples,
+        )?;
+        for cmd in candidates {
+            if depth >= self.max_size {
+                break;
+            }
+            commands.push((Var(depth), cmd.clone()));
+            let mut sequence = CommandSequence::new(self.state_machine.clone(), commands.clone());
+            let mut sys = (self.system_under_test_factory)();
+            match sequence.run(&mut sys) {
+                Err(e) => {
+                    self.max_size = depth;
+                    self.failure = Some((e.to_string(), sequence));
+                }
+                Ok(()) => {
+                    let mut next_state = state_machine.clone();
+                    next_state.next_state_symbolic(&cmd, Var(depth));
+                    let key = format!("{:?}", next_state);
+                    if self.visited.get(&key).is_none_or(|d| depth + 1 < *d) {
+                        self.visited.insert(key, depth + 1);
+                        self.explore(&next_state, commands)?;
+                    }
+                }
+            }
+            commands.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Run every command sequence of up to `Config::max_sequence_size` commands against
+/// the provided system-under-test, instead of sampling them at random. The sequences
+/// are enumerated depth-first from the commands offered by the model, and sequences
+/// leading to a model state which was already reached by a sequence at most as long
+/// are pruned. The shortest failing command sequence is reported. The test run is
+/// aborted when sampling the command strategies does not exhaust their domain within
+/// `Config::max_enumeration_samples` samples.
+pub fn run_exhaustive<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let mut initial_state = state_machine.clone();
+    initial_state.reset();
+    let mut search = Search {
+        state_machine,
+        system_under_test_factory: &system_under_test_factory,
+        runner: TestRunner::new(config.proptest.clone()),
+        samples: config.enumeration_samples,
+        max_samples: config.max_enumeration_samples,
+        max_size: config.max_sequence_size,
+        visited: HashMap::new(),
+        failure: None,
+    };
+    search.visited.insert(format!("{:?}", initial_state), 0);
+    let result = match (search.explore(&initial_state, &mut Vec::new()), search.failure) {
+        (Err(reason), _) => Err(TestError::Abort(reason)),
+        (Ok(()), Some((reason, commands))) => Err(TestError::Fail(reason.into(), commands)),
+        (Ok(()), None) => Ok(()),
+    };
+    report_failure(result, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use proptest::strategy::Strategy;
+
+    use crate::{
+        run_exhaustive, Config, Error, Result, StateMachine, SystemUnderTest, TestFailure,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Add(usize);
+
+    // Counter modulo 10, whose commands are enumerated by the model when `enumerated`
+    // is set, and sampled from their strategy otherwise
+    #[derive(Clone, Debug)]
+    struct ModuloModel {
+        value: usize,
+        enumerated: bool,
+    }
+
+    impl StateMachine for ModuloModel {
+        type Command = Add;
+
+        type CommandResult = usize;
+
+        fn reset(&mut self) {
+            self.value = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![(1, (1..=3usize).prop_map(Add).boxed())]
+        }
+
+        fn enumerate_commands(&self) -> Option<Vec<Self::Command>> {
+            self.enumerated.then(|| vec![Add(1), Add(2), Add(3)])
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            self.value = (self.value + cmd.0) % 10;
+        }
+    }
+
+    // Fails whenever the counter reaches `failing`
+    struct Modulo {
+        value: usize,
+        failing: usize,
+    }
+
+    impl SystemUnderTest<Add, usize> for Modulo {
+        fn run(&mut self, cmd: &Add) -> Result<usize> {
+            self.value = (self.value + cmd.0) % 10;
+            if self.value == self.failing {
+                return Err(Error::system_under_test(std::io::Error::other(
+                    "Unexpected value",
+                )));
+            }
+            Ok(self.value)
+        }
+    }
+
+    #[test]
+    fn exhaustive_search_finds_shortest_failure() {
+        let config = Config {
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        let model = ModuloModel {
+            value: 0,
+            enumerated: false,
+        };
+        let result = run_exhaustive(config, model, || {
+            Box::new(Modulo {
+                value: 0,
+                failing: 9,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(seq.commands, vec![Add(3); 3]),
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[test]
+    fn exhaustive_search_caps_enumeration_samples() {
+        // Too few samples to tell the domain of the strategy apart from an infinite one
+        let config = Config {
+            max_enumeration_samples: 2,
+            ..Config::default()
+        };
+        let model = ModuloModel {
+            value: 0,
+            enumerated: false,
+        };
+        let result = run_exhaustive(config, model, || {
+            Box::new(Modulo {
+                value: 0,
+                failing: 10,
+            })
+        });
+        match result {
+            Err(TestFailure::Abort(reason)) => assert!(reason
+                .message()
+                .starts_with("Command strategies still yield new commands after 2 samples")),
+            _ => panic!("Test should have been aborted"),
+        }
+    }
+
+    #[test]
+    fn exhaustive_search_prunes_duplicate_states() {
+        let config = Config {
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        let model = ModuloModel {
+            value: 0,
+            enumerated: true,
+        };
+        let runs = Cell::new(0);
+        let result = run_exhaustive(config, model, || {
+            runs.set(runs.get() + 1);
+            Box::new(Modulo {
+                value: 0,
+                failing: 10,
+            })
+        });
+        assert!(result.is_ok());
+        // Each of the 10 states is only expanded again when it is reached by a shorter
+        // sequence, instead of enumerating the 3^20 sequences
+        assert!(runs.get() < 200, "{} sequences were run", runs.get());
+    }
+}
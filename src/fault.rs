@@ -0,0 +1,249 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{fmt::Debug, thread, time::Duration};
+
+use proptest::{
+    option,
+    prelude::prop_oneof,
+    strategy::{BoxedStrategy, Just, Strategy},
+};
+
+use crate::{label, Result, SystemUnderTest};
+
+/// Fault injected between the harness and the system-under-test
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Fault {
+    /// The command is not delivered to the system-under-test
+    Drop,
+    /// The command is delivered, but its result is replaced by an error
+    Error,
+    /// The command is delivered after the given delay
+    Delay(Duration),
+    /// The command is delivered twice. The result of the first delivery is returned.
+    Duplicate,
+}
+
+impl Fault {
+    /// Strategy generating every kind of fault, with delays of up to `max_delay`
+    pub fn strategy(max_delay: Duration) -> BoxedStrategy<Fault> {
+        let max_delay = max_delay.as_millis() as u64;
+        prop_oneof![
+            Just(Fault::Drop),
+            Just(Fault::Error),
+            (0..=max_delay).prop_map(|ms| Fault::Delay(Duration::from_millis(ms))),
+            Just(Fault::Duplicate),
+        ]
+        .boxed()
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Fault::Drop => "Drop",
+            Fault::Error => "Error",
+            Fault::Delay(_) => "Delay",
+            Fault::Duplicate => "Duplicate",
+        }
+    }
+}
+
+/// Command of the model together with the fault injected when delivering it, if any.
+/// Models of systems wrapped in a `FaultInjectingSystem` use it as their command type,
+/// so that they can predict the outcome of each fault.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Faulty<C> {
+    pub command: C,
+    pub fault: Option<Fault>,
+}
+
+/// Wrap the weighted command strategies of a model, so that a fault generated by
+/// `faults` is injected with the given probability when delivering each command.
+/// When `Config::shrink_commands` is set, faults are removed from the commands of a
+/// failing command sequence whenever the failure does not depend on them.
+pub fn inject_faults<C>(
+    commands: Vec<(usize, BoxedStrategy<C>)>,
+    probability: f64,
+    faults: BoxedStrategy<Fault>,
+) -> Vec<(usize, BoxedStrategy<Faulty<C>>)>
+where
+    C: Clone + Debug + 'static,
+{
+    commands
+        .into_iter()
+        .map(|(weight, strategy)| {
+            let strategy = (strategy, option::weighted(probability, faults.clone()))
+                .prop_map(|(command, fault)| Faulty { command, fault })
+                .boxed();
+            (weight, strategy)
+        })
+        .collect()
+}
+
+/// Wrapper around a system-under-test which injects the fault carried by each command.
+/// The result of a command is `Err` with the fault when the fault prevents the result
+/// from reaching the harness (`Fault::Drop` and `Fault::Error`). Each injected fault is
+/// recorded as a label in the statistics of the test run.
+pub struct FaultInjectingSystem<C, R> {
+    system_under_test: Box<dyn SystemUnderTest<C, R>>,
+}
+
+impl<C, R> FaultInjectingSystem<C, R> {
+    pub fn new(system_under_test: Box<dyn SystemUnderTest<C, R>>) -> Self {
+        FaultInjectingSystem { system_under_test }
+    }
+}
+
+impl<C, R> SystemUnderTest<Faulty<C>, std::result::Result<R, Fault>>
+    for FaultInjectingSystem<C, R>
+{
+    fn run(&mut self, cmd: &Faulty<C>) -> Result<std::result::Result<R, Fault>> {
+        let fault = match cmd.fault {
+            Some(fault) => fault,
+            None => return Ok(Ok(self.system_under_test.run(&cmd.command)?)),
+        };
+        label(format!("Injected fault: {}", fault.name()));
+        match fault {
+            Fault::Drop => Ok(Err(fault)),
+            Fault::Error => {
+                self.system_under_test.run(&cmd.command)?;
+                Ok(Err(fault))
+            }
+            Fault::Delay(delay) => {
+                thread::sleep(delay);
+                Ok(Ok(self.system_under_test.run(&cmd.command)?))
+            }
+            Fault::Duplicate => {
+                let result = self.system_under_test.run(&cmd.command)?;
+                self.system_under_test.run(&cmd.command)?;
+                Ok(Ok(result))
+            }
+        }
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        self.system_under_test.cleanup()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.system_under_test.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::strategy::{Just, Strategy};
+
+    use crate::test_models::{Counter, CounterCommand};
+    use crate::{
+        inject_faults, run, run_with_statistics, Config, Error, Fault, FaultInjectingSystem,
+        Faulty, Result, StateMachine, SystemUnderTest, TestFailure,
+    };
+
+    // Predicts the outcome of the faults, unless `ignore_duplicates` is set
+    #[derive(Clone, Debug)]
+    struct CounterModel {
+        value: usize,
+        ignore_duplicates: bool,
+    }
+
+    impl StateMachine for CounterModel {
+        type Command = Faulty<CounterCommand>;
+
+        type CommandResult = std::result::Result<usize, Fault>;
+
+        fn reset(&mut self) {
+            self.value = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            inject_faults(
+                vec![(1, Just(CounterCommand::Increment).boxed())],
+                0.2,
+                Fault::strategy(Duration::from_millis(2)),
+            )
+        }
+
+        fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+            let expected = match cmd.fault {
+                Some(fault @ Fault::Drop) | Some(fault @ Fault::Error) => Err(fault),
+                _ => Ok(self.value + 1),
+            };
+            if *res != expected {
+                return Err(Error::postcondition(
+                    format!("{:?}", cmd),
+                    format!("{:?}", expected),
+                    format!("{:?}", res),
+                ));
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            self.value += match cmd.fault {
+                Some(Fault::Drop) => 0,
+                Some(Fault::Duplicate) if !self.ignore_duplicates => 2,
+                _ => 1,
+            };
+        }
+    }
+
+    fn config() -> Config {
+        let mut config = Config {
+            max_sequence_size: 20,
+            shrink_commands: true,
+            ..Config::default()
+        };
+        config.proptest.cases = 32;
+        config
+    }
+
+    fn counter(
+    ) -> Box<dyn SystemUnderTest<Faulty<CounterCommand>, std::result::Result<usize, Fault>>> {
+        Box::new(FaultInjectingSystem::new(Box::new(Counter { value: 0 })))
+    }
+
+    #[test]
+    fn injected_faults_are_predicted_by_model() {
+        let model = CounterModel {
+            value: 0,
+            ignore_duplicates: false,
+        };
+        let statistics = run_with_statistics(config(), model, counter).unwrap();
+        assert!(statistics
+            .labels
+            .keys()
+            .any(|label| label.starts_with("Injected fault")));
+    }
+
+    #[test]
+    fn unexpected_faults_are_shrunk() {
+        let model = CounterModel {
+            value: 0,
+            ignore_duplicates: true,
+        };
+        match run(config(), model, counter) {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(
+                seq.commands,
+                vec![
+                    Faulty {
+                        command: CounterCommand::Increment,
+                        fault: Some(Fault::Duplicate)
+                    },
+                    Faulty {
+                        command: CounterCommand::Increment,
+                        fault: None
+                    }
+                ]
+            ),
+            _ => panic!("Test should have failed"),
+        }
+    }
+}
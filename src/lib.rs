@@ -5,21 +5,70 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "async")]
+mod asynchronous;
 mod config;
+mod differential;
 mod errors;
+mod exhaustive;
+mod fault;
+mod macros;
+mod parallel;
+#[cfg(feature = "serde")]
+mod persistence;
+#[cfg(feature = "process")]
+mod process;
+mod regression;
+mod statistics;
+mod symbolic;
+mod targeted;
+#[cfg(test)]
+mod test_models;
 mod traits;
 
-use std::{fmt::Debug, marker::PhantomData};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    fmt::Debug,
+    marker::PhantomData,
+    ops::Range,
+};
 
 use proptest::{
     strategy::{BoxedStrategy, NewTree, Strategy, ValueTree},
-    test_runner::{TestError, TestRunner},
+    test_runner::{Reason, TestError, TestRunner},
+};
+use rand::distr::{weighted::WeightedIndex, Distribution, Uniform};
+
+#[cfg(feature = "async")]
+pub use asynchronous::{run_async, AsyncSystemUnderTest, BlockingExecutor, BoxFuture, Executor};
+pub use config::{Config, Shrinker, SizeDistribution};
+pub use differential::run_differential;
+pub use errors::{Error, Result, TestFailure};
+pub use exhaustive::run_exhaustive;
+pub use fault::{inject_faults, Fault, FaultInjectingSystem, Faulty};
+pub use macros::assert_passed;
+pub use parallel::{
+    run_parallel, ParallelCommandSequence, ParallelCommandSequenceStrategy,
+    ParallelCommandSequenceValueTree,
 };
-use rand::distributions::{uniform::Uniform, Distribution, WeightedIndex};
+#[cfg(feature = "process")]
+pub use process::ProcessSystem;
+pub use statistics::{label, Statistics};
+pub use symbolic::{Environment, Symbolic, Var};
+pub use targeted::{run_targeted, target};
+pub use traits::{
+    Commands, ConcurrentSystemUnderTest, ObservableStateMachine, ObservableSystem,
+    PersistentCommand, StateMachine, SystemUnderTest,
+};
+
+#[cfg(feature = "derive")]
+pub use proptest_stateful_derive::Commands;
 
-pub use config::Config;
-pub use errors::{Error, Result};
-pub use traits::{StateMachine, SystemUnderTest};
+// Referred to by the code generated by `#[derive(Commands)]`, so that deriving does not
+// require a direct dependency on proptest
+#[doc(hidden)]
+pub use proptest as __proptest;
 
 #[derive(Debug)]
 pub struct CommandSequence<SM>
@@ -27,25 +76,165 @@ where
     SM: StateMachine,
 {
     commands: Vec<SM::Command>,
+    variables: Vec<Var>,
     state_machine: SM,
 }
 
 impl<SM> CommandSequence<SM>
 where
     SM: StateMachine,
+{
+    /// Construct a command sequence from a list of commands, each with the symbolic
+    /// variable to which its result is bound
+    pub fn new(state_machine: SM, commands: Vec<(Var, SM::Command)>) -> Self {
+        let (variables, commands) = commands.into_iter().unzip();
+        CommandSequence {
+            commands,
+            variables,
+            state_machine,
+        }
+    }
+
+    /// Returns the number of commands in the sequence
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Check whether the sequence holds no command
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+impl<SM> std::fmt::Display for CommandSequence<SM>
+where
+    SM: StateMachine,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (var, cmd) in self.variables.iter().zip(&self.commands) {
+            writeln!(f, "{} = {:?}", var, cmd)?;
+        }
+        Ok(())
+    }
+}
+
+impl<SM> CommandSequence<SM>
+where
+    SM: StateMachine + Debug,
 {
     pub fn run(
         &mut self,
         system_under_test: &mut Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
     ) -> Result<()> {
-        self.state_machine.reset();
-        for cmd in &self.commands {
-            let result = system_under_test.run(cmd)?;
-            self.state_machine.postcondition(&cmd, &result)?;
-            self.state_machine.next_state(&cmd);
+        self.run_steps(&mut **system_under_test, |_, _, _, _| Ok(()))
+    }
+
+    /// Run the command sequence, additionally comparing the observation of the
+    /// system-under-test with the observation of the model after each command
+    pub fn run_observed(
+        &mut self,
+        system_under_test: &mut Box<
+            dyn ObservableSystem<SM::Command, SM::CommandResult, SM::Observation>,
+        >,
+    ) -> Result<()>
+    where
+        SM: ObservableStateMachine,
+    {
+        self.run_steps(&mut **system_under_test, |state_machine, cmd, step, sut| {
+            let expected = state_machine.observe();
+            let actual = sut.observe()?;
+            if expected != actual {
+                return Err(Error::observation(
+                    step,
+                    format!("{:?}", cmd),
+                    format!("{:?}", expected),
+                    format!("{:?}", actual),
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    /// Run the command sequence, calling `after_step` once each command has been
+    /// applied to both the system-under-test and the model. The system-under-test is
+    /// cleaned up afterwards, even if a command failed. A failing cleanup is reported
+    /// as `Error::Cleanup`, which holds the error of the command sequence, if any, so
+    /// that the original failure is not masked.
+    fn run_steps<SUT, F>(&mut self, system_under_test: &mut SUT, after_step: F) -> Result<()>
+    where
+        SUT: SystemUnderTest<SM::Command, SM::CommandResult> + ?Sized,
+        F: FnMut(&SM, &SM::Command, usize, &SUT) -> Result<()>,
+    {
+        let result = self.apply_steps(system_under_test, after_step);
+        with_cleanup(result, system_under_test.cleanup())
+    }
+
+    fn apply_steps<SUT, F>(&mut self, system_under_test: &mut SUT, mut after_step: F) -> Result<()>
+    where
+        SUT: SystemUnderTest<SM::Command, SM::CommandResult> + ?Sized,
+        F: FnMut(&SM, &SM::Command, usize, &SUT) -> Result<()>,
+    {
+        let mut steps = Steps::new(&mut self.state_machine);
+        for (step, (cmd, var)) in self.commands.iter().zip(&self.variables).enumerate() {
+            let concrete_cmd = steps.resolve(cmd)?;
+            let result = system_under_test.run(&concrete_cmd)?;
+            steps.check(step, *var, cmd, result)?;
+            after_step(steps.state_machine, cmd, step, system_under_test)?;
+        }
+        Ok(())
+    }
+}
+
+/// Model and environment of a command sequence while its commands are run one after
+/// the other against the system-under-test
+struct Steps<'a, SM>
+where
+    SM: StateMachine,
+{
+    state_machine: &'a mut SM,
+    environment: Environment<SM::CommandResult>,
+}
+
+impl<'a, SM> Steps<'a, SM>
+where
+    SM: StateMachine + Debug,
+{
+    /// Reset the model before running the first command
+    fn new(state_machine: &'a mut SM) -> Self {
+        state_machine.reset();
+        Steps {
+            state_machine,
+            environment: Environment::new(),
         }
+    }
+
+    /// Returns the command to be run against the system-under-test
+    fn resolve(&self, cmd: &SM::Command) -> Result<SM::Command> {
+        self.state_machine.resolve(cmd, &self.environment)
+    }
+
+    /// Check the result of the command at position `step` against the model, then
+    /// advance the model and bind the result to the variable of the command
+    fn check(
+        &mut self,
+        step: usize,
+        var: Var,
+        cmd: &SM::Command,
+        result: SM::CommandResult,
+    ) -> Result<()> {
+        self.state_machine.postcondition(cmd, &result)?;
+        self.state_machine.next_state_symbolic(cmd, var);
+        check_invariant(self.state_machine, step)?;
+        self.environment.bind(var, result);
         Ok(())
     }
+
+    /// Advance the model and bind the result to the variable of the command, without
+    /// checking the result
+    fn advance(&mut self, var: Var, cmd: &SM::Command, result: SM::CommandResult) {
+        self.state_machine.next_state_symbolic(cmd, var);
+        self.environment.bind(var, result);
+    }
 }
 
 impl<SM> IntoIterator for CommandSequence<SM>
@@ -61,9 +250,89 @@ where
     }
 }
 
+/// Combine the result of a command sequence with the result of the cleanup of the
+/// system-under-test, keeping the error of the sequence in a failing cleanup
+fn with_cleanup(result: Result<()>, cleanup: Result<()>) -> Result<()> {
+    match (result, cleanup) {
+        (Ok(()), Err(e)) => Err(Error::cleanup(e)),
+        (Err(e), Err(cleanup)) => Err(Error::cleanup_after(e, cleanup)),
+        (result, Ok(())) => result,
+    }
+}
+
+/// State of the model after replaying a prefix of the command sequence
+struct Replay<SM>
+where
+    SM: StateMachine,
+{
+    state_machine: SM,
+    bound: HashSet<Var>,
+    commands: Vec<(Var, SM::Command)>,
+}
+
+/// Deletion of commands from a failing command sequence, according to the shrinker.
+/// Chunks of commands are deleted in turn, from the first to the last one: single
+/// commands for the sequential shrinker, and chunks of halving size for delta
+/// debugging. Passes are repeated until no deletion is kept, so that commands which
+/// were needed before a later deletion are attempted again.
+struct Deletion {
+    shrinker: Shrinker,
+    size: usize,
+    // Position of the chunk among the included commands
+    start: usize,
+    // Whether a deletion was kept since the last pass with single deletions started
+    progress: bool,
+}
+
+impl Deletion {
+    fn new(shrinker: Shrinker, num_included: usize) -> Self {
+        Deletion {
+            shrinker,
+            size: Self::first_size(shrinker, num_included),
+            start: 0,
+            progress: false,
+        }
+    }
+
+    fn first_size(shrinker: Shrinker, num_included: usize) -> usize {
+        match shrinker {
+            Shrinker::Sequential => 1,
+            Shrinker::DeltaDebugging => num_included.div_ceil(2).max(1),
+        }
+    }
+
+    /// Positions among the included commands of the next chunk to delete, or `None`
+    /// once a pass with single deletions ends without any deletion being kept
+    fn next(&mut self, num_included: usize) -> Option<Range<usize>> {
+        while self.start >= num_included {
+            if self.size > 1 {
+                self.size = self.size.div_ceil(2);
+            } else if std::mem::take(&mut self.progress) {
+                self.size = Self::first_size(self.shrinker, num_included);
+            } else {
+                return None;
+            }
+            self.start = 0;
+        }
+        Some(self.start..(self.start + self.size).min(num_included))
+    }
+
+    /// The last deletion was kept: the commands following it moved to its position
+    fn kept(&mut self) {
+        self.progress = true;
+    }
+
+    /// The last deletion was undone: move on to the next chunk
+    fn undone(&mut self) {
+        self.start += self.size;
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Shrink {
-    DeleteCommand(usize),
+    Delete,
+    ReplaceCommand { index: usize, candidate: usize },
     ShrinkCommand(usize),
 }
 pub struct CommandSequenceValueTree<SM>
@@ -72,19 +341,167 @@ where
 {
     elements: Vec<Box<dyn ValueTree<Value = SM::Command>>>,
     included: Vec<bool>,
+    // Commands offered by `StateMachine::shrink_command` in place of the elements
+    replacements: Vec<Option<SM::Command>>,
+    // Replacement undone by `complicate`
+    prev_replacement: Option<SM::Command>,
     state_machine: SM,
     shrink: Shrink,
     prev_shrink: Option<Shrink>,
     shrink_commands: bool,
+    // Minimum number of commands kept when deleting commands
+    min_size: usize,
+    deletion: Deletion,
+    // Elements removed by the last deletion
+    deleted: Vec<usize>,
+    shrinker: Shrinker,
+    // Whether any simplification was kept since the current round of deletions and
+    // command simplifications started
+    round_progress: bool,
 }
 
 impl<SM> CommandSequenceValueTree<SM>
 where
-    SM: StateMachine,
+    SM: StateMachine + Clone + Debug,
 {
     fn num_included(&self) -> usize {
         self.included.iter().filter(|&x| *x).count()
     }
+
+    fn start_deletions(&mut self) {
+        self.deletion = Deletion::new(self.shrinker, self.num_included());
+        self.shrink = Shrink::Delete;
+    }
+
+    /// Check whether the commands which remain applicable, once the model is replayed,
+    /// are fewer than the lower bound of the shrinker
+    fn below_min_size(&self) -> bool {
+        self.current().commands.len() < self.min_size
+    }
+
+    fn included_indices(&self) -> Vec<usize> {
+        (0..self.elements.len())
+            .filter(|&x| self.included[x])
+            .collect()
+    }
+
+    /// Delete the next chunk of commands, skipping deletions which would leave fewer
+    /// commands than the lower bound of the shrinker
+    fn delete(&mut self) -> bool {
+        while let Shrink::Delete = self.shrink {
+            let included = self.included_indices();
+            let chunk = match self.deletion.next(included.len()) {
+                Some(chunk) => chunk,
+                None => {
+                    self.shrink = Shrink::ReplaceCommand {
+                        index: 0,
+                        candidate: 0,
+                    };
+                    break;
+                }
+            };
+            self.deleted = included[chunk].to_vec();
+            for &index in &self.deleted {
+                self.included[index] = false;
+            }
+            if self.below_min_size() {
+                for &index in &self.deleted {
+                    self.included[index] = true;
+                }
+                self.deletion.undone();
+                continue;
+            }
+            self.prev_shrink = Some(Shrink::Delete);
+            return true;
+        }
+        false
+    }
+
+    fn command(&self, index: usize) -> SM::Command {
+        self.replacements[index]
+            .clone()
+            .unwrap_or_else(|| self.elements[index].current())
+    }
+
+    /// Replay the model over the included commands preceding `end`, skipping any command
+    /// whose precondition no longer holds, which references a variable that is no
+    /// longer bound or whose next state breaks the invariants of the model, after
+    /// earlier commands were removed or simplified
+    fn replay(&self, end: usize) -> Replay<SM> {
+        let mut state_machine = self.state_machine.clone();
+        state_machine.reset();
+        let mut bound = HashSet::new();
+        let mut commands = Vec::with_capacity(end);
+        for index in (0..end).filter(|&x| self.included[x]) {
+            let command = self.command(index);
+            let var = Var(index);
+            if !applicable(&state_machine, &bound, &command) {
+                continue;
+            }
+            let mut next_state_machine = state_machine.clone();
+            next_state_machine.next_state_symbolic(&command, var);
+            if next_state_machine.invariant().is_err() {
+                continue;
+            }
+            state_machine = next_state_machine;
+            bound.insert(var);
+            commands.push((var, command));
+        }
+        Replay {
+            state_machine,
+            bound,
+            commands,
+        }
+    }
+
+    /// Replace the commands with the simpler candidates offered by the model, as long
+    /// as every command of the sequence remains applicable
+    fn replace_command(&mut self) -> bool {
+        while let Shrink::ReplaceCommand { index, candidate } = self.shrink {
+            if index >= self.elements.len() {
+                self.shrink = Shrink::ShrinkCommand(0);
+                break;
+            }
+
+            let command = self.command(index);
+            let replay = self.replay(index);
+            let candidates = if self.included[index]
+                && applicable(&replay.state_machine, &replay.bound, &command)
+            {
+                replay.state_machine.shrink_command(&command)
+            } else {
+                Vec::new()
+            };
+            if candidate >= candidates.len() {
+                self.shrink = Shrink::ReplaceCommand {
+                    index: index + 1,
+                    candidate: 0,
+                };
+                continue;
+            }
+
+            let num_commands = self.replay(self.elements.len()).commands.len();
+            let previous = self.replacements[index].replace(candidates[candidate].clone());
+            if self.replay(self.elements.len()).commands.len() < num_commands {
+                self.replacements[index] = previous;
+                self.shrink = Shrink::ReplaceCommand {
+                    index,
+                    candidate: candidate + 1,
+                };
+                continue;
+            }
+
+            // The replacement may itself be replaced by a simpler command
+            self.prev_replacement = previous;
+            self.prev_shrink = Some(self.shrink);
+            self.shrink = Shrink::ReplaceCommand {
+                index,
+                candidate: 0,
+            };
+            return true;
+        }
+        false
+    }
 }
 
 impl<SM> ValueTree for CommandSequenceValueTree<SM>
@@ -94,71 +511,90 @@ where
     type Value = CommandSequence<SM>;
 
     fn current(&self) -> Self::Value {
-        let commands = self
-            .elements
-            .iter()
-            .enumerate()
-            .filter(|&(x, _)| self.included[x])
-            .map(|(_, element)| element.current())
-            .collect();
+        let (variables, commands) = self
+            .replay(self.elements.len())
+            .commands
+            .into_iter()
+            .unzip();
         CommandSequence {
             commands,
+            variables,
             state_machine: self.state_machine.clone(),
         }
     }
 
     fn simplify(&mut self) -> bool {
-        if let Shrink::DeleteCommand(index) = self.shrink {
-            if index >= self.elements.len() || self.num_included() == 1 {
-                self.shrink = Shrink::ShrinkCommand(0);
-            } else {
-                self.included[index] = false;
-                self.prev_shrink = Some(self.shrink);
-                self.shrink = Shrink::DeleteCommand(index + 1);
-                return true;
+        // The previous simplification was kept, since it was not undone by `complicate`
+        if let Some(prev_shrink) = self.prev_shrink {
+            self.round_progress = true;
+            if let Shrink::Delete = prev_shrink {
+                self.deletion.kept();
             }
         }
 
-        if !self.shrink_commands {
-            return false;
-        }
-
-        while let Shrink::ShrinkCommand(index) = self.shrink {
-            if index >= self.elements.len() {
-                return false;
+        loop {
+            if self.delete() {
+                return true;
             }
 
-            if !self.included[index] {
-                self.shrink = Shrink::ShrinkCommand(index + 1);
-                continue;
+            if self.replace_command() {
+                return true;
             }
 
-            if !self.elements[index].simplify() {
-                self.shrink = Shrink::ShrinkCommand(index + 1);
-            } else {
-                self.prev_shrink = Some(self.shrink);
-                return true;
+            while let Shrink::ShrinkCommand(index) = self.shrink {
+                if !self.shrink_commands || index >= self.elements.len() {
+                    // Simplified commands may have made other commands redundant, so
+                    // start another round of deletions, until no progress is made
+                    if !std::mem::take(&mut self.round_progress) {
+                        return false;
+                    }
+                    self.start_deletions();
+                    break;
+                }
+
+                if !self.included[index] || self.replacements[index].is_some() {
+                    self.shrink = Shrink::ShrinkCommand(index + 1);
+                    continue;
+                }
+
+                if !self.elements[index].simplify() {
+                    self.shrink = Shrink::ShrinkCommand(index + 1);
+                } else {
+                    self.prev_shrink = Some(self.shrink);
+                    return true;
+                }
             }
         }
-
-        panic!("Unexpected shrink state");
     }
 
     fn complicate(&mut self) -> bool {
         match self.prev_shrink {
             None => false,
-            Some(Shrink::DeleteCommand(index)) => {
-                self.included[index] = true;
+            Some(Shrink::Delete) => {
+                for &index in &self.deleted {
+                    self.included[index] = true;
+                }
+                self.deletion.undone();
+                self.prev_shrink = None;
+                true
+            }
+            Some(Shrink::ReplaceCommand { index, candidate }) => {
+                self.replacements[index] = self.prev_replacement.take();
+                self.shrink = Shrink::ReplaceCommand {
+                    index,
+                    candidate: candidate + 1,
+                };
                 self.prev_shrink = None;
                 true
             }
             Some(Shrink::ShrinkCommand(ix)) => {
-                if self.elements[ix].complicate() {
-                    true
-                } else {
+                if !self.elements[ix].complicate() {
+                    // The command is back to its last failing value. The runner stops
+                    // shrinking when `complicate` returns false, so report a change and
+                    // let it confirm the failure before the next simplification.
                     self.prev_shrink = None;
-                    false
                 }
+                true
             }
         }
     }
@@ -173,7 +609,16 @@ where
     state_machine: SM,
     min_size: usize,
     max_size: usize,
+    size_distribution: SizeDistribution,
+    num_cases: usize,
+    // Number of command sequences generated so far
+    num_generated: Cell<usize>,
+    stop_without_commands: bool,
     shrink_commands: bool,
+    shrinker: Shrinker,
+    min_shrink_size: usize,
+    // Error of the library which made the generation of a command sequence fail
+    generation_error: RefCell<Option<Error>>,
     _strategy: PhantomData<S>,
 }
 
@@ -182,16 +627,37 @@ where
     S: Strategy,
     SM: StateMachine + Clone,
 {
-    fn new(min_size: usize, max_size: usize, shrink_commands: bool, state_machine: SM) -> Self {
-        assert!(max_size >= min_size);
+    fn new(config: &Config, state_machine: SM) -> Self {
+        assert!(config.max_sequence_size >= config.min_sequence_size);
         CommandSequenceStrategy {
             state_machine,
-            min_size,
-            max_size,
-            shrink_commands,
+            min_size: config.min_sequence_size,
+            max_size: config.max_sequence_size,
+            size_distribution: config.size_distribution,
+            num_cases: config.proptest.cases as usize,
+            num_generated: Cell::new(0),
+            stop_without_commands: config.stop_without_commands,
+            shrink_commands: config.shrink_commands,
+            shrinker: config.shrinker,
+            min_shrink_size: config.min_shrink_size,
+            generation_error: RefCell::new(None),
             _strategy: PhantomData,
         }
     }
+
+    /// Maximum length of the next command sequence, which grows with the number of
+    /// command sequences generated so far, according to the size distribution
+    fn next_max_size(&self) -> usize {
+        let case = self.num_generated.replace(self.num_generated.get() + 1);
+        let range = (self.max_size - self.min_size) as f64;
+        let progress = ((case + 1) as f64 / self.num_cases.max(1) as f64).min(1.0);
+        let growth = match self.size_distribution {
+            SizeDistribution::Uniform => range,
+            SizeDistribution::Linear => range * progress,
+            SizeDistribution::Geometric => (range + 1.0).powf(progress) - 1.0,
+        };
+        self.min_size + growth.round() as usize
+    }
 }
 
 impl<S, SM> Strategy for CommandSequenceStrategy<S, SM>
@@ -202,37 +668,145 @@ where
     type Tree = CommandSequenceValueTree<SM>;
     type Value = CommandSequence<SM>;
 
-    fn new_tree(&self, runner: &mut proptest::test_runner::TestRunner) -> NewTree<Self> {
-        let size = Uniform::new_inclusive(self.min_size, self.max_size).sample(runner.rng());
+    fn new_tree(&self, runner: &mut TestRunner) -> NewTree<Self> {
+        let size = Uniform::new_inclusive(self.min_size, self.next_max_size())
+            .map_err(|e| e.to_string())?
+            .sample(runner.rng());
 
         let mut state_machine = self.state_machine.clone();
         state_machine.reset();
         let mut elements = Vec::with_capacity(size);
-        while elements.len() < size {
-            let possible_commands = state_machine.commands();
-            let weights = possible_commands
-                .iter()
-                .map(|(w, _)| *w)
-                .collect::<Vec<usize>>();
-            let choice = WeightedIndex::new(&weights)
-                .map_err(|e| e.to_string())?
-                .sample(runner.rng());
-            let (_, ref command_strategy) = possible_commands[choice];
-            let command = command_strategy.new_tree(runner)?;
-            state_machine.next_state(&command.current());
-            elements.push(command);
-        }
+        generate_commands(
+            runner,
+            &mut state_machine,
+            size,
+            self.stop_without_commands,
+            &self.generation_error,
+            &mut elements,
+        )?;
+        Ok(self.value_tree(elements))
+    }
+}
+
+impl<S, SM> CommandSequenceStrategy<S, SM>
+where
+    S: Strategy,
+    SM: StateMachine + Clone + Debug,
+{
+    /// Build the value tree which shrinks the generated commands
+    fn value_tree(
+        &self,
+        elements: Vec<Box<dyn ValueTree<Value = SM::Command>>>,
+    ) -> CommandSequenceValueTree<SM> {
+        let mut state_machine = self.state_machine.clone();
         state_machine.reset();
         let num_elements = elements.len();
-        Ok(CommandSequenceValueTree {
+        CommandSequenceValueTree {
             elements,
             included: vec![true; num_elements],
+            replacements: vec![None; num_elements],
+            prev_replacement: None,
             state_machine,
-            shrink: Shrink::DeleteCommand(0),
+            shrink: Shrink::Delete,
             prev_shrink: None,
             shrink_commands: self.shrink_commands,
-        })
+            min_size: self.min_shrink_size,
+            deletion: Deletion::new(self.shrinker, num_elements),
+            deleted: Vec::new(),
+            shrinker: self.shrinker,
+            round_progress: false,
+        }
+    }
+}
+
+/// Check that the model is not in a terminal state, that the command only references
+/// bound variables and that its precondition holds in the current state of the model
+fn applicable<SM>(state_machine: &SM, bound: &HashSet<Var>, cmd: &SM::Command) -> bool
+where
+    SM: StateMachine,
+{
+    !state_machine.is_terminal()
+        && state_machine
+            .variables(cmd)
+            .iter()
+            .all(|v| bound.contains(v))
+        && state_machine.precondition(cmd)
+}
+
+/// Check the invariants of the model after the state transition caused by the command
+/// at position `step`, recording the step and the model state in the returned error
+fn check_invariant<SM>(state_machine: &SM, step: usize) -> Result<()>
+where
+    SM: StateMachine + Debug,
+{
+    state_machine.invariant().map_err(|e| {
+        let message = match e {
+            Error::Invariant { message, .. } => message,
+            e => e.to_string(),
+        };
+        Error::Invariant {
+            step,
+            state: format!("{:?}", state_machine),
+            message,
+        }
+    })
+}
+
+/// Generate `size` commands starting from the current state of the model and append
+/// them to `elements`. The result of each command is bound to the symbolic variable
+/// given by its position in `elements`. Generation stops early once the model reaches a
+/// terminal state. When the model offers no command, generation either stops early or
+/// fails, depending on `stop_without_commands`, storing `Error::NoCommand` in
+/// `generation_error` for `report_failure`, since the runner only keeps its message.
+fn generate_commands<SM>(
+    runner: &mut TestRunner,
+    state_machine: &mut SM,
+    size: usize,
+    stop_without_commands: bool,
+    generation_error: &RefCell<Option<Error>>,
+    elements: &mut Vec<Box<dyn ValueTree<Value = SM::Command>>>,
+) -> std::result::Result<(), Reason>
+where
+    SM: StateMachine + Debug,
+{
+    let target = elements.len() + size;
+    while elements.len() < target && !state_machine.is_terminal() {
+        let possible_commands = state_machine.commands();
+        let choice = match weighted_choice(runner, &possible_commands)? {
+            Some(choice) => choice,
+            None if stop_without_commands => break,
+            None => {
+                let error = Error::no_command(elements.len(), format!("{:?}", state_machine));
+                let reason = error.to_string().into();
+                *generation_error.borrow_mut() = Some(error);
+                return Err(reason);
+            }
+        };
+        let (_, ref command_strategy) = possible_commands[choice];
+        let command = command_strategy.new_tree(runner)?;
+        if !state_machine.precondition(&command.current()) {
+            runner.reject_local("Command precondition does not hold")?;
+            continue;
+        }
+        state_machine.next_state_symbolic(&command.current(), Var(elements.len()));
+        check_invariant(state_machine, elements.len()).map_err(|e| e.to_string())?;
+        elements.push(command);
+    }
+    Ok(())
+}
+
+/// Sample the index of one of the weighted choices, if any has a positive weight
+fn weighted_choice<T>(
+    runner: &mut TestRunner,
+    choices: &[(usize, T)],
+) -> std::result::Result<Option<usize>, Reason> {
+    if choices.iter().all(|(w, _)| *w == 0) {
+        return Ok(None);
     }
+    let choice = WeightedIndex::new(choices.iter().map(|(w, _)| *w))
+        .map_err(|e| e.to_string())?
+        .sample(runner.rng());
+    Ok(Some(choice))
 }
 
 fn command_sequence<SM>(
@@ -242,45 +816,222 @@ fn command_sequence<SM>(
 where
     SM: StateMachine + Clone,
 {
-    CommandSequenceStrategy::new(config.min_sequence_size, config.max_sequence_size, config.shrink_commands, state_machine)
+    CommandSequenceStrategy::new(config, state_machine)
 }
 
 /// Run a set of tests for the provided system-under-test, using the given
-/// state machine model and configuration parameters
+/// state machine model and configuration parameters. With the `serde` feature, the
+/// failing command sequences stored in the regressions file are replayed first, and
+/// the minimal failing case of the run is stored there (see `Config::regressions_file`).
 pub fn run<SM, SUTF>(
     config: Config,
     state_machine: SM,
     system_under_test_factory: SUTF,
-) -> std::result::Result<(), TestError<CommandSequence<SM>>>
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + std::fmt::Debug,
+    SM::Command: PersistentCommand,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let print_statistics = config.print_statistics;
+    let statistics = run_with_statistics(config, state_machine, system_under_test_factory)?;
+    if print_statistics {
+        println!("{}", statistics);
+    }
+    Ok(())
+}
+
+/// Run a set of tests like `run`, returning the statistics of the command sequences
+/// once all test cases have passed
+pub fn run_with_statistics<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<Statistics, TestFailure<CommandSequence<SM>>>
 where
     SM: StateMachine + Clone + std::fmt::Debug,
+    SM::Command: PersistentCommand,
     SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
 {
+    #[cfg(feature = "serde")]
+    persistence::replay(&config, &state_machine, &system_under_test_factory)?;
+
     let mut runner = TestRunner::new(config.proptest.clone());
+    let statistics = RefCell::new(Statistics::default());
 
-    let result = runner.run(
-        &command_sequence(&config, state_machine),
-        |mut commands| {
-            let mut sys = system_under_test_factory();
-            commands.run(&mut sys)?;
-            Ok(())
+    let strategy = command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let mut sys = system_under_test_factory();
+        let (result, labels) = statistics::collect_labels(|| commands.run(&mut sys));
+        result?;
+        statistics.borrow_mut().record(&commands, labels);
+        Ok(())
+    });
+    let result = report_failure(result, strategy.generation_error.take());
+    #[cfg(feature = "serde")]
+    persistence::store(&config, &result);
+    result.map(|_| statistics.into_inner())
+}
+
+/// Run a set of tests like `run`, but build the system-under-test only once and reuse
+/// it for all the test cases, restoring it between command sequences through
+/// `SystemUnderTest::reset`. When the reset fails, the system is either rebuilt from the
+/// factory or the test case fails with `Error::Reset`, depending on
+/// `Config::rebuild_on_failed_reset`. Systems are cleaned up only when they are discarded:
+/// a failing cleanup of a system discarded after a failed reset fails the test case with
+/// `Error::Cleanup`, and a failing cleanup of the system once the test cases have run
+/// aborts the test run, or is appended to the reason of the minimal failing case.
+pub fn run_reused<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + std::fmt::Debug,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+    let rebuild = config.rebuild_on_failed_reset;
+    let system = RefCell::new(None);
+
+    let strategy = command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let mut system = system.borrow_mut();
+        let sys = reset_system(&mut system, &system_under_test_factory, rebuild)?;
+        commands.apply_steps(&mut **sys, |_, _, _, _| Ok(()))?;
+        Ok(())
+    });
+    let cleanup = match system.into_inner() {
+        Some(mut sys) => sys.cleanup(),
+        None => Ok(()),
+    };
+    let result = report_failure(result, strategy.generation_error.take());
+    let e = match cleanup {
+        Ok(()) => return result,
+        Err(e) => e,
+    };
+    let with_cleanup_failure = |reason: Reason| -> Reason {
+        format!(
+            "{} (cleanup of the system-under-test failed as well: {})",
+            reason.message(),
+            e
+        )
+        .into()
+    };
+    match result {
+        Ok(()) => Err(TestFailure::Abort(Error::cleanup(e).to_string().into())),
+        Err(TestFailure::Fail(reason, commands)) => {
+            Err(TestFailure::Fail(with_cleanup_failure(reason), commands))
+        }
+        Err(TestFailure::BeforeAnyCommand(reason)) => {
+            Err(TestFailure::BeforeAnyCommand(with_cleanup_failure(reason)))
+        }
+        result => result,
+    }
+}
+
+/// Prepare the reused system-under-test for the next command sequence, building it
+/// on first use
+fn reset_system<'a, C, R, SUTF>(
+    system: &'a mut Option<Box<dyn SystemUnderTest<C, R>>>,
+    system_under_test_factory: &SUTF,
+    rebuild: bool,
+) -> Result<&'a mut Box<dyn SystemUnderTest<C, R>>>
+where
+    SUTF: Fn() -> Box<dyn SystemUnderTest<C, R>>,
+{
+    let mut sys = match system.take() {
+        Some(sys) => sys,
+        None => return Ok(system.insert(system_under_test_factory())),
+    };
+    match sys.reset() {
+        Ok(()) => Ok(system.insert(sys)),
+        Err(e) if rebuild => match sys.cleanup() {
+            Ok(()) => Ok(system.insert(system_under_test_factory())),
+            Err(cleanup) => Err(Error::cleanup_after(Error::reset(e), cleanup)),
         },
-    );
-    if let Err(e) = &result {
-        println!("Found minimal failing case: {}", e);
+        Err(e) => {
+            *system = Some(sys);
+            Err(Error::reset(e))
+        }
+    }
+}
+
+/// Run a set of tests for the provided observable system-under-test, using the given
+/// state machine model and configuration parameters. In addition to checking the
+/// postconditions, the observation of the system-under-test is compared with the
+/// observation of the model after each command.
+pub fn run_observed<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: ObservableStateMachine + Clone + std::fmt::Debug,
+    SUTF: Fn() -> Box<dyn ObservableSystem<SM::Command, SM::CommandResult, SM::Observation>>,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+
+    let strategy = command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let mut sys = system_under_test_factory();
+        commands.run_observed(&mut sys)?;
+        Ok(())
+    });
+    report_failure(result, strategy.generation_error.take())
+}
+
+/// Minimal failing case found by a runner
+trait FailingCase: Debug {
+    /// Check whether the failing case holds no command
+    fn is_empty(&self) -> bool;
+}
+
+impl<SM> FailingCase for CommandSequence<SM>
+where
+    SM: StateMachine + Debug,
+{
+    fn is_empty(&self) -> bool {
+        CommandSequence::is_empty(self)
     }
-    result
+}
+
+/// Convert the result of the runner into the result of the test run. A test run aborted
+/// because a command sequence could not be generated fails with the error stored by
+/// `generate_commands`, whose message is all the runner keeps, and an empty minimal
+/// failing case is reported as `TestFailure::BeforeAnyCommand`.
+fn report_failure<T, F>(
+    result: std::result::Result<T, TestError<F>>,
+    generation_error: Option<Error>,
+) -> std::result::Result<T, TestFailure<F>>
+where
+    F: FailingCase,
+{
+    result.map_err(|e| match (e, generation_error) {
+        (TestError::Abort(_), Some(error)) => TestFailure::Generation(error),
+        (TestError::Fail(reason, commands), _) if commands.is_empty() => {
+            TestFailure::BeforeAnyCommand(reason)
+        }
+        (e, _) => e.into(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use std::cell::Cell;
+    use std::{cell::Cell, rc::Rc};
 
-    use proptest::strategy::{Just, Strategy};
-    use proptest::test_runner::TestError;
+    use proptest::strategy::{Just, Strategy, ValueTree};
+    use proptest::test_runner::TestRunner;
 
-    use crate::{config::Config, errors::Result, run, Error, StateMachine};
-    use crate::{CommandSequence, SystemUnderTest};
+    use crate::test_models::{StackCommand, StackModel, StackSystem};
+    use crate::{
+        command_sequence, config::Config, errors::Result, run, run_reused, run_with_statistics,
+        Error, Shrinker, SizeDistribution, StateMachine, TestFailure,
+    };
+    use crate::{
+        CommandSequence, Environment, ObservableStateMachine, ObservableSystem, Symbolic,
+        SystemUnderTest, Var,
+    };
 
     #[derive(Clone, Debug)]
     struct TestModel {
@@ -291,6 +1042,7 @@ mod tests {
     }
 
     impl TestModel {
+        #[allow(clippy::match_like_matches_macro)]
         fn new(plan: Vec<TestCommand>) -> TestModel {
             let target = plan
                 .iter()
@@ -309,6 +1061,7 @@ mod tests {
     }
 
     #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     enum TestCommand {
         Up { tag: usize },
         Down,
@@ -365,13 +1118,14 @@ mod tests {
         }
     }
 
+    #[allow(clippy::assertions_on_constants)]
     fn check_result<SM: StateMachine>(
-        result: std::result::Result<(), TestError<CommandSequence<SM>>>,
+        result: std::result::Result<(), TestFailure<CommandSequence<SM>>>,
         model: &TestModel,
     ) {
         match result {
             Err(test_error) => match test_error {
-                TestError::Fail(_, seq) => {
+                TestFailure::Fail(_, seq) => {
                     assert_eq!(
                         seq.commands.len(),
                         model.target,
@@ -385,6 +1139,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn shrink_removes_sequence_head() {
         let plan = vec![
             TestCommand::Down,
@@ -404,6 +1159,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn shrink_removes_sequence_tail() {
         let plan = vec![
             TestCommand::Up { tag: 1 },
@@ -422,6 +1178,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn shrink_removes_arbitrary() {
         let plan = vec![
             TestCommand::Down,
@@ -444,4 +1201,944 @@ mod tests {
         let result = run(config, model.clone(), || Box::new(TestSystem));
         check_result(result, &model);
     }
+
+    // The stack overflows once it holds two elements
+    #[test]
+    fn shrink_respects_preconditions() {
+        let mut config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run(config, StackModel { depth: 0 }, || {
+            Box::new(StackSystem {
+                depth: 0,
+                capacity: 1,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(
+                seq.commands,
+                vec![StackCommand::Push, StackCommand::Push],
+                "Invalid minimal sequence"
+            ),
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[test]
+    fn delta_debugging_shrinks_long_sequences() {
+        let mut config = Config {
+            min_sequence_size: 100,
+            max_sequence_size: 100,
+            shrinker: Shrinker::DeltaDebugging,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 100;
+        let result = run(config, StackModel { depth: 0 }, || {
+            Box::new(StackSystem {
+                depth: 0,
+                capacity: 1,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(
+                seq.commands,
+                vec![StackCommand::Push, StackCommand::Push],
+                "Invalid minimal sequence"
+            ),
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct CounterModel;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    struct Add(usize);
+
+    impl StateMachine for CounterModel {
+        type Command = Add;
+
+        type CommandResult = ();
+
+        fn reset(&mut self) {}
+
+        // Amounts shrink towards the largest one
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![(1, (0..1000usize).prop_map(|n| Add(1000 - n)).boxed())]
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {}
+    }
+
+    // Overflows once the total reaches 1000
+    struct SmallCounter {
+        total: usize,
+    }
+
+    impl SystemUnderTest<Add, ()> for SmallCounter {
+        fn run(&mut self, cmd: &Add) -> Result<()> {
+            self.total += cmd.0;
+            if self.total >= 1000 {
+                return Err(Error::system_under_test(std::io::Error::other(
+                    "Counter overflow",
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shrink_deletes_commands_made_redundant_by_simplification() {
+        let mut config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            shrink_commands: true,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run(config, CounterModel, || Box::new(SmallCounter { total: 0 }));
+        match result {
+            Err(TestFailure::Fail(_, seq)) => {
+                assert_eq!(seq.commands, vec![Add(1000)], "Invalid minimal sequence")
+            }
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct RegisterModel {
+        value: usize,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum RegisterCommand {
+        Write(usize),
+        Read,
+    }
+
+    impl StateMachine for RegisterModel {
+        type Command = RegisterCommand;
+
+        type CommandResult = usize;
+
+        fn reset(&mut self) {
+            self.value = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![
+                (3, (0..10usize).prop_map(RegisterCommand::Write).boxed()),
+                (1, Just(RegisterCommand::Read).boxed()),
+            ]
+        }
+
+        fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+            if *cmd == RegisterCommand::Read && *res != self.value {
+                return Result::Err(Error::postcondition(
+                    format!("{:?}", cmd),
+                    format!("{:?}", self.value),
+                    format!("{:?}", res),
+                ));
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            if let RegisterCommand::Write(value) = *cmd {
+                self.value = value;
+            }
+        }
+
+        fn shrink_command(&self, cmd: &Self::Command) -> Vec<Self::Command> {
+            match *cmd {
+                RegisterCommand::Write(_) => vec![RegisterCommand::Read],
+                RegisterCommand::Read => Vec::new(),
+            }
+        }
+    }
+
+    // Crashes on the third command, whatever the command is
+    struct CrashingRegister {
+        value: usize,
+        num_commands: usize,
+    }
+
+    impl SystemUnderTest<RegisterCommand, usize> for CrashingRegister {
+        fn run(&mut self, cmd: &RegisterCommand) -> Result<usize> {
+            self.num_commands += 1;
+            if self.num_commands == 3 {
+                return Err(Error::system_under_test(std::io::Error::other("Crash")));
+            }
+            if let RegisterCommand::Write(value) = *cmd {
+                self.value = value;
+            }
+            Ok(self.value)
+        }
+    }
+
+    #[test]
+    fn shrink_replaces_commands_with_simpler_ones() {
+        let mut config = Config {
+            min_sequence_size: 10,
+            max_sequence_size: 10,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run(config, RegisterModel { value: 0 }, || {
+            Box::new(CrashingRegister {
+                value: 0,
+                num_commands: 0,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(
+                seq.commands,
+                vec![RegisterCommand::Read; 3],
+                "Invalid minimal sequence"
+            ),
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    // Offers a limited number of commands
+    #[derive(Clone, Debug)]
+    struct CountdownModel {
+        remaining: usize,
+    }
+
+    impl StateMachine for CountdownModel {
+        type Command = ();
+
+        type CommandResult = ();
+
+        fn reset(&mut self) {
+            self.remaining = 3;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            if self.remaining == 0 {
+                return Vec::new();
+            }
+            vec![(1, Just(()).boxed())]
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {
+            self.remaining -= 1;
+        }
+    }
+
+    struct Countdown;
+
+    impl SystemUnderTest<(), ()> for Countdown {
+        fn run(&mut self, _cmd: &()) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn generation_without_commands() {
+        let config = |stop_without_commands| Config {
+            min_sequence_size: 5,
+            max_sequence_size: 5,
+            stop_without_commands,
+            ..Config::default()
+        };
+
+        match run(config(false), CountdownModel { remaining: 3 }, || {
+            Box::new(Countdown)
+        }) {
+            Err(TestFailure::Generation(Error::NoCommand { step, state })) => {
+                assert_eq!(step, 3);
+                assert_eq!(state, "CountdownModel { remaining: 0 }");
+            }
+            _ => panic!("Generation should have failed"),
+        }
+
+        let statistics = run_with_statistics(config(true), CountdownModel { remaining: 3 }, || {
+            Box::new(Countdown)
+        })
+        .expect("Test should have passed");
+        assert!(statistics
+            .sequence_lengths
+            .keys()
+            .all(|length| *length == 3));
+    }
+
+    #[derive(Clone, Debug)]
+    struct ConnectionModel {
+        closed: bool,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum ConnectionCommand {
+        Send,
+        Close,
+    }
+
+    impl StateMachine for ConnectionModel {
+        type Command = ConnectionCommand;
+
+        type CommandResult = ();
+
+        fn reset(&mut self) {
+            self.closed = false;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![
+                (3, Just(ConnectionCommand::Send).boxed()),
+                (1, Just(ConnectionCommand::Close).boxed()),
+            ]
+        }
+
+        fn is_terminal(&self) -> bool {
+            self.closed
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            if *cmd == ConnectionCommand::Close {
+                self.closed = true;
+            }
+        }
+    }
+
+    #[test]
+    fn generation_stops_in_terminal_state() {
+        let config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        let strategy = command_sequence(&config, ConnectionModel { closed: false });
+        let mut runner = TestRunner::default();
+        for _ in 0..100 {
+            let mut tree = strategy.new_tree(&mut runner).unwrap();
+            let commands = tree.current().commands;
+            assert!(commands.len() == 20 || commands.last() == Some(&ConnectionCommand::Close));
+            // Shrink as if every command sequence failed
+            while tree.simplify() {
+                let commands = tree.current().commands;
+                if let Some(close) = commands.iter().position(|c| *c == ConnectionCommand::Close) {
+                    assert_eq!(close, commands.len() - 1, "Command after a terminal state");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_length_grows_with_test_cases() {
+        let config = |size_distribution| {
+            let mut config = Config {
+                min_sequence_size: 1,
+                max_sequence_size: 100,
+                size_distribution,
+                ..Config::default()
+            };
+            config.proptest.cases = 10;
+            config
+        };
+        let max_lengths = |size_distribution| {
+            let strategy = command_sequence(&config(size_distribution), CounterModel);
+            (0..10)
+                .map(|_| strategy.next_max_size())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(max_lengths(SizeDistribution::Uniform), vec![100; 10]);
+        assert_eq!(
+            max_lengths(SizeDistribution::Linear),
+            vec![11, 21, 31, 41, 51, 60, 70, 80, 90, 100]
+        );
+        let geometric = max_lengths(SizeDistribution::Geometric);
+        assert_eq!(geometric.first(), Some(&2));
+        assert_eq!(geometric.last(), Some(&100));
+        assert!(geometric.windows(2).all(|w| w[0] <= w[1]));
+
+        let strategy = command_sequence(&config(SizeDistribution::Linear), CounterModel);
+        let tree = strategy.new_tree(&mut TestRunner::default()).unwrap();
+        assert!(tree.current().commands.len() <= 11);
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct HandleModel {
+        handles: Vec<Var>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum HandleCommand {
+        Open,
+        Read { handle: Symbolic<usize> },
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum HandleResult {
+        Handle(usize),
+        Value(Option<usize>),
+    }
+
+    impl StateMachine for HandleModel {
+        type Command = HandleCommand;
+
+        type CommandResult = HandleResult;
+
+        fn reset(&mut self) {
+            self.handles.clear();
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            let mut options = vec![(1, Just(HandleCommand::Open).boxed())];
+            if !self.handles.is_empty() {
+                options.push((
+                    1,
+                    proptest::sample::select(self.handles.clone())
+                        .prop_map(|var| HandleCommand::Read { handle: var.into() })
+                        .boxed(),
+                ));
+            }
+            options
+        }
+
+        fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+            if let HandleCommand::Read { .. } = cmd {
+                if *res != HandleResult::Value(None) {
+                    return Result::Err(Error::postcondition(
+                        format!("{:?}", cmd),
+                        format!("{:?}", HandleResult::Value(None)),
+                        format!("{:?}", res),
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {}
+
+        fn next_state_symbolic(&mut self, cmd: &Self::Command, var: Var) {
+            if let HandleCommand::Open = cmd {
+                self.handles.push(var);
+            }
+        }
+
+        fn variables(&self, cmd: &Self::Command) -> Vec<Var> {
+            match cmd {
+                HandleCommand::Read { handle } => handle.var().into_iter().collect(),
+                HandleCommand::Open => Vec::new(),
+            }
+        }
+
+        fn resolve(
+            &self,
+            cmd: &Self::Command,
+            env: &Environment<Self::CommandResult>,
+        ) -> Result<Self::Command> {
+            match cmd {
+                HandleCommand::Read {
+                    handle: Symbolic::Var(var),
+                } => match env.get(*var)? {
+                    HandleResult::Handle(handle) => Ok(HandleCommand::Read {
+                        handle: Symbolic::Value(*handle),
+                    }),
+                    HandleResult::Value(_) => Err(Error::unbound_variable(*var)),
+                },
+                _ => Ok(cmd.clone()),
+            }
+        }
+    }
+
+    struct HandleSystem {
+        next_handle: usize,
+    }
+
+    impl SystemUnderTest<HandleCommand, HandleResult> for HandleSystem {
+        fn run(&mut self, cmd: &HandleCommand) -> Result<HandleResult> {
+            match cmd {
+                HandleCommand::Open => {
+                    self.next_handle += 1;
+                    Ok(HandleResult::Handle(self.next_handle))
+                }
+                // Reading from the second handle which was opened returns garbage
+                HandleCommand::Read {
+                    handle: Symbolic::Value(handle),
+                } => Ok(HandleResult::Value(if *handle == 101 {
+                    Some(0)
+                } else {
+                    None
+                })),
+                HandleCommand::Read { .. } => panic!("Symbolic command was not resolved"),
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_drops_commands_with_unbound_variables() {
+        let mut config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run(config, HandleModel::default(), || {
+            Box::new(HandleSystem { next_handle: 99 })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => {
+                assert_eq!(seq.commands.len(), 3, "Invalid minimal sequence length");
+                assert_eq!(
+                    seq.commands[2],
+                    HandleCommand::Read {
+                        handle: Symbolic::Var(seq.variables[1])
+                    }
+                );
+            }
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct BoundedStackModel {
+        depth: usize,
+        max_depth: usize,
+    }
+
+    impl StateMachine for BoundedStackModel {
+        type Command = StackCommand;
+
+        type CommandResult = usize;
+
+        fn reset(&mut self) {
+            self.depth = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![(1, Just(StackCommand::Push).boxed())]
+        }
+
+        fn invariant(&self) -> Result<()> {
+            if self.depth > self.max_depth {
+                return Err(Error::invariant("Stack is too deep"));
+            }
+            Ok(())
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            match *cmd {
+                StackCommand::Push => self.depth += 1,
+                StackCommand::Pop => self.depth -= 1,
+            }
+        }
+    }
+
+    #[test]
+    fn invariant_reports_step_and_state() {
+        let mut commands = CommandSequence {
+            commands: vec![StackCommand::Push; 3],
+            variables: vec![Var(0), Var(1), Var(2)],
+            state_machine: BoundedStackModel {
+                depth: 0,
+                max_depth: 1,
+            },
+        };
+        let mut sys: Box<dyn SystemUnderTest<StackCommand, usize>> = Box::new(StackSystem {
+            depth: 0,
+            capacity: usize::MAX,
+        });
+        match commands.run(&mut sys) {
+            Err(Error::Invariant {
+                step,
+                state,
+                message,
+            }) => {
+                assert_eq!(step, 1);
+                assert!(state.contains("depth: 2"));
+                assert_eq!(message, "Stack is too deep");
+            }
+            _ => panic!("Invariant should not hold"),
+        }
+    }
+
+    #[test]
+    fn invariant_checked_during_generation() {
+        let config = Config {
+            min_sequence_size: 3,
+            max_sequence_size: 3,
+            ..Config::default()
+        };
+        let model = BoundedStackModel {
+            depth: 0,
+            max_depth: 1,
+        };
+        let result = run(config, model, || {
+            Box::new(StackSystem {
+                depth: 0,
+                capacity: usize::MAX,
+            })
+        });
+        match result {
+            Err(TestFailure::Abort(reason)) => {
+                assert!(reason
+                    .message()
+                    .contains("Invariant does not hold after step 1"))
+            }
+            _ => panic!("Test should have been aborted"),
+        }
+    }
+
+    // Only offers pushes on stacks holding fewer than two elements
+    #[derive(Clone, Debug)]
+    struct ShallowStackModel {
+        depth: usize,
+    }
+
+    impl StateMachine for ShallowStackModel {
+        type Command = StackCommand;
+
+        type CommandResult = usize;
+
+        fn reset(&mut self) {
+            self.depth = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            let mut commands = vec![(1, Just(StackCommand::Pop).boxed())];
+            if self.depth < 2 {
+                commands.push((1, Just(StackCommand::Push).boxed()));
+            }
+            commands
+        }
+
+        fn precondition(&self, cmd: &Self::Command) -> bool {
+            *cmd == StackCommand::Push || self.depth > 0
+        }
+
+        fn invariant(&self) -> Result<()> {
+            if self.depth > 2 {
+                return Err(Error::invariant("Stack is too deep"));
+            }
+            Ok(())
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            match *cmd {
+                StackCommand::Push => self.depth += 1,
+                StackCommand::Pop => self.depth -= 1,
+            }
+        }
+    }
+
+    // Crashes on the twelfth command, whatever the command is
+    struct CrashingStackSystem {
+        stack: StackSystem,
+        num_commands: usize,
+    }
+
+    impl SystemUnderTest<StackCommand, usize> for CrashingStackSystem {
+        fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+            self.num_commands += 1;
+            if self.num_commands == 12 {
+                return Err(Error::system_under_test(std::io::Error::other("Crash")));
+            }
+            self.stack.run(cmd)
+        }
+    }
+
+    #[test]
+    fn shrink_respects_invariants() {
+        let mut config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run(config, ShallowStackModel { depth: 0 }, || {
+            Box::new(CrashingStackSystem {
+                stack: StackSystem {
+                    depth: 0,
+                    capacity: usize::MAX,
+                },
+                num_commands: 0,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(reason, seq)) => {
+                assert_eq!(reason.message(), "Crash");
+                assert_eq!(seq.commands.len(), 12, "Invalid minimal sequence length");
+            }
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    impl ObservableStateMachine for StackModel {
+        type Observation = usize;
+
+        fn observe(&self) -> Self::Observation {
+            self.depth
+        }
+    }
+
+    // Reports the number of elements ever pushed instead of the current depth
+    struct LeakyStackSystem {
+        depth: usize,
+        pushed: usize,
+    }
+
+    impl SystemUnderTest<StackCommand, usize> for LeakyStackSystem {
+        fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+            match *cmd {
+                StackCommand::Push => {
+                    self.depth += 1;
+                    self.pushed += 1;
+                }
+                StackCommand::Pop => self.depth -= 1,
+            }
+            Ok(self.depth)
+        }
+    }
+
+    impl ObservableSystem<StackCommand, usize, usize> for LeakyStackSystem {
+        fn observe(&self) -> Result<usize> {
+            Ok(self.pushed)
+        }
+    }
+
+    #[test]
+    fn observation_mismatch_reports_step() {
+        let mut commands = CommandSequence {
+            commands: vec![StackCommand::Push, StackCommand::Pop],
+            variables: vec![Var(0), Var(1)],
+            state_machine: StackModel { depth: 0 },
+        };
+        let mut sys: Box<dyn ObservableSystem<StackCommand, usize, usize>> =
+            Box::new(LeakyStackSystem {
+                depth: 0,
+                pushed: 0,
+            });
+        match commands.run_observed(&mut sys) {
+            Err(Error::Observation {
+                step,
+                command,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(step, 1);
+                assert_eq!(command, "Pop");
+                assert_eq!(expected, "0");
+                assert_eq!(actual, "1");
+            }
+            _ => panic!("Observations should not match"),
+        }
+    }
+
+    // Counts its cleanups, failing them on request
+    struct DisposableStackSystem {
+        stack: StackSystem,
+        cleanups: Rc<Cell<usize>>,
+        fail_cleanup: bool,
+    }
+
+    impl SystemUnderTest<StackCommand, usize> for DisposableStackSystem {
+        fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+            self.stack.run(cmd)
+        }
+
+        fn cleanup(&mut self) -> Result<()> {
+            self.cleanups.set(self.cleanups.get() + 1);
+            if self.fail_cleanup {
+                return Err(Error::system_under_test(std::io::Error::other(
+                    "Could not release the stack",
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cleanup_runs_after_failure_without_masking_it() {
+        let cleanups = Rc::new(Cell::new(0));
+        let system = |fail_cleanup| -> Box<dyn SystemUnderTest<StackCommand, usize>> {
+            Box::new(DisposableStackSystem {
+                stack: StackSystem {
+                    depth: 0,
+                    capacity: usize::MAX,
+                },
+                cleanups: cleanups.clone(),
+                fail_cleanup,
+            })
+        };
+
+        let mut commands =
+            CommandSequence::new(StackModel { depth: 0 }, vec![(Var(0), StackCommand::Pop)]);
+        match commands.run(&mut system(true)) {
+            Err(Error::Cleanup {
+                original: Some(original),
+                ..
+            }) => assert!(matches!(*original, Error::SystemUnderTest { .. })),
+            _ => panic!("Cleanup should have failed after the command"),
+        }
+        assert_eq!(cleanups.get(), 1);
+
+        let mut commands =
+            CommandSequence::new(StackModel { depth: 0 }, vec![(Var(0), StackCommand::Push)]);
+        assert!(commands.run(&mut system(false)).is_ok());
+        assert!(matches!(
+            commands.run(&mut system(true)),
+            Err(Error::Cleanup { original: None, .. })
+        ));
+        assert_eq!(cleanups.get(), 3);
+    }
+
+    #[test]
+    fn shrink_lower_bound_is_configurable() {
+        // The cleanup of the system always fails, whatever the commands
+        let config = |min_shrink_size| {
+            let mut config = Config {
+                min_sequence_size: 3,
+                max_sequence_size: 10,
+                min_shrink_size,
+                ..Config::default()
+            };
+            config.proptest.max_shrink_iters = 1000;
+            config
+        };
+        let factory = || -> Box<dyn SystemUnderTest<StackCommand, usize>> {
+            Box::new(DisposableStackSystem {
+                stack: StackSystem {
+                    depth: 0,
+                    capacity: usize::MAX,
+                },
+                cleanups: Rc::new(Cell::new(0)),
+                fail_cleanup: true,
+            })
+        };
+
+        match run(config(3), StackModel { depth: 0 }, factory) {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(seq.commands.len(), 3),
+            _ => panic!("Test should have failed"),
+        }
+        match run(config(0), StackModel { depth: 0 }, factory) {
+            Err(TestFailure::BeforeAnyCommand(_)) => {}
+            _ => panic!("Test should have failed before any command"),
+        }
+    }
+
+    struct ResettableStackSystem {
+        stack: StackSystem,
+        fail_reset: bool,
+    }
+
+    impl SystemUnderTest<StackCommand, usize> for ResettableStackSystem {
+        fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+            self.stack.run(cmd)
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            if self.fail_reset {
+                return Err(Error::system_under_test(std::io::Error::other(
+                    "Could not empty the stack",
+                )));
+            }
+            self.stack.depth = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reused_system_is_reset_between_cases() {
+        // A stack which is not emptied between the sequences eventually overflows
+        let config = |rebuild_on_failed_reset| {
+            let mut config = Config {
+                min_sequence_size: 1,
+                max_sequence_size: 1,
+                rebuild_on_failed_reset,
+                ..Config::default()
+            };
+            config.proptest.cases = 20;
+            config
+        };
+        let builds = Cell::new(0);
+        let factory = |fail_reset| {
+            let builds = &builds;
+            move || -> Box<dyn SystemUnderTest<StackCommand, usize>> {
+                builds.set(builds.get() + 1);
+                Box::new(ResettableStackSystem {
+                    stack: StackSystem {
+                        depth: 0,
+                        capacity: 1,
+                    },
+                    fail_reset,
+                })
+            }
+        };
+
+        assert!(run_reused(config(true), StackModel { depth: 0 }, factory(false)).is_ok());
+        assert_eq!(builds.get(), 1);
+
+        builds.set(0);
+        assert!(run_reused(config(true), StackModel { depth: 0 }, factory(true)).is_ok());
+        assert_eq!(builds.get(), 20);
+
+        match run_reused(config(false), StackModel { depth: 0 }, factory(true)) {
+            Err(TestFailure::Fail(reason, _)) => {
+                assert!(reason
+                    .message()
+                    .contains("Reset of the system-under-test failed"))
+            }
+            _ => panic!("Reset should have failed"),
+        }
+    }
+
+    #[test]
+    fn regression_test_source() {
+        let commands = CommandSequence::new(
+            StackModel { depth: 0 },
+            vec![(Var(0), StackCommand::Push), (Var(2), StackCommand::Pop)],
+        );
+        let source = commands.to_regression_test(
+            "stack_regression",
+            "StackModel { depth: 0 }",
+            "Box::new(StackSystem { depth: 0 })",
+            |cmd| format!("StackCommand::{:?}", cmd),
+        );
+        assert_eq!(
+            source,
+            r#"#[test]
+fn stack_regression() {
+    let mut commands = proptest_stateful::CommandSequence::new(
+        StackModel { depth: 0 },
+        vec![
+            (proptest_stateful::Var(0), StackCommand::Push),
+            (proptest_stateful::Var(2), StackCommand::Pop),
+        ],
+    );
+    let mut sys: Box<dyn proptest_stateful::SystemUnderTest<_, _>> = Box::new(StackSystem { depth: 0 });
+    commands.run(&mut sys).unwrap_or_else(|e| panic!("{}", e));
+}
+"#
+        );
+    }
 }
@@ -0,0 +1,185 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{CommandSequence, StateMachine, TestFailure};
+
+/// Returns the output of a successful test run, or panics with the reason of the
+/// failure and the minimal failing command sequence, one command per line
+pub fn assert_passed<T, SM>(result: std::result::Result<T, TestFailure<CommandSequence<SM>>>) -> T
+where
+    SM: StateMachine,
+{
+    match result {
+        Ok(output) => output,
+        Err(TestFailure::Fail(reason, commands)) => panic!(
+            "Test failed: {}\nMinimal failing command sequence:\n{}",
+            reason.message(),
+            commands
+        ),
+        Err(TestFailure::Abort(reason)) => panic!("Test aborted: {}", reason.message()),
+        Err(TestFailure::BeforeAnyCommand(reason)) => panic!(
+            "Test failed before any command was run: {}",
+            reason.message()
+        ),
+        Err(TestFailure::Generation(e)) => panic!("Command sequence could not be generated: {}", e),
+    }
+}
+
+/// Declare test functions which run a stateful property test, in the spirit of
+/// `proptest!`. Each function gives the expression constructing the model, the
+/// expression constructing the system-under-test (which is boxed by the macro) and,
+/// optionally, the configuration and the function running the test (default: `run`).
+/// Unless set by the configuration, `proptest.source_file` is the file invoking the
+/// macro. The test panics with the minimal failing command sequence on failure.
+///
+/// ```ignore
+/// state_machine_test! {
+///     #[test]
+///     fn cache() {
+///         model: CacheModel::new(10),
+///         system: Cache::new(10).unwrap(),
+///         config: Config { print_statistics: true, ..Config::default() },
+///         runner: run_observed,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! state_machine_test {
+    (@config) => {
+        $crate::Config::default()
+    };
+    (@config $config:expr) => {
+        $config
+    };
+    (@runner) => {
+        $crate::run
+    };
+    (@runner $runner:path) => {
+        $runner
+    };
+    ($(
+        $(#[$meta:meta])*
+        fn $name:ident() {
+            model: $model:expr,
+            system: $system:expr
+            $(, config: $config:expr)?
+            $(, runner: $runner:path)?
+            $(,)?
+        }
+    )*) => {$(
+        $(#[$meta])*
+        fn $name() {
+            let mut config: $crate::Config = $crate::state_machine_test!(@config $($config)?);
+            if config.proptest.source_file.is_none() {
+                config.proptest.source_file = Some(file!());
+            }
+            let result = $crate::state_machine_test!(@runner $($runner)?)(config, $model, || {
+                ::std::boxed::Box::new($system)
+            });
+            $crate::assert_passed(result);
+        }
+    )*};
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::strategy::{Just, Strategy};
+
+    use crate::{Config, Error, Result, StateMachine, SystemUnderTest};
+
+    #[derive(Clone, Debug)]
+    struct LatchModel {
+        set: bool,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum LatchCommand {
+        Set,
+        Read,
+    }
+
+    impl StateMachine for LatchModel {
+        type Command = LatchCommand;
+
+        type CommandResult = bool;
+
+        fn reset(&mut self) {
+            self.set = false;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![
+                (1, Just(LatchCommand::Set).boxed()),
+                (1, Just(LatchCommand::Read).boxed()),
+            ]
+        }
+
+        fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+            if let LatchCommand::Read = cmd {
+                if *res != self.set {
+                    return Err(Error::postcondition(
+                        format!("{:?}", cmd),
+                        format!("{:?}", self.set),
+                        format!("{:?}", res),
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            if let LatchCommand::Set = cmd {
+                self.set = true;
+            }
+        }
+    }
+
+    // Forgets that it was set whenever `forgetful` is true
+    struct Latch {
+        set: bool,
+        forgetful: bool,
+    }
+
+    impl SystemUnderTest<LatchCommand, bool> for Latch {
+        fn run(&mut self, cmd: &LatchCommand) -> Result<bool> {
+            match *cmd {
+                LatchCommand::Set => self.set = !self.forgetful,
+                LatchCommand::Read => {}
+            }
+            Ok(self.set)
+        }
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.proptest.cases = 32;
+        config.proptest.failure_persistence = None;
+        // Keep the failing sequences of `forgetful_latch` out of the source tree
+        config.regressions_file =
+            Some(std::env::temp_dir().join("proptest-stateful-macros.sequences"));
+        config
+    }
+
+    state_machine_test! {
+        #[test]
+        fn latch() {
+            model: LatchModel { set: false },
+            system: Latch { set: false, forgetful: false },
+            config: config(),
+        }
+
+        #[test]
+        #[should_panic(expected = "Actual result: false\nMinimal failing command sequence:\nvar")]
+        fn forgetful_latch() {
+            model: LatchModel { set: false },
+            system: Latch { set: false, forgetful: true },
+            config: config(),
+            runner: crate::run_with_statistics,
+        }
+    }
+}
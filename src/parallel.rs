@@ -0,0 +1,658 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use proptest::{
+    strategy::{NewTree, Strategy, ValueTree},
+    test_runner::TestRunner,
+};
+
+use crate::{
+    applicable, generate_commands, report_failure, ConcurrentSystemUnderTest, Config, Deletion,
+    Error, FailingCase, Result, Shrinker, StateMachine, Steps, TestFailure, Var,
+};
+
+/// A command sequence made up of a sequential prefix, followed by a number of
+/// branches which are run concurrently against the system-under-test
+#[derive(Debug)]
+pub struct ParallelCommandSequence<SM>
+where
+    SM: StateMachine,
+{
+    prefix: Vec<(Var, SM::Command)>,
+    branches: Vec<Vec<(Var, SM::Command)>>,
+    state_machine: SM,
+}
+
+/// Failure of a command of a concurrent branch. Errors of the system-under-test are not
+/// required to be `Send`, so they are replaced by their message before leaving the
+/// thread running the branch.
+#[derive(Debug)]
+struct BranchError(String);
+
+impl From<Error> for BranchError {
+    fn from(e: Error) -> Self {
+        BranchError(e.to_string())
+    }
+}
+
+impl std::error::Error for BranchError {}
+
+impl std::fmt::Display for BranchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Response of the system-under-test to a command of a concurrent branch, together
+/// with the logical times at which the command was invoked and returned
+struct Event<R> {
+    result: R,
+    invoked: usize,
+    returned: usize,
+}
+
+impl<SM> ParallelCommandSequence<SM>
+where
+    SM: StateMachine,
+{
+    /// Check whether the sequence holds no command, neither in its prefix nor in its
+    /// branches
+    pub fn is_empty(&self) -> bool {
+        self.prefix.is_empty() && self.branches.iter().all(|branch| branch.is_empty())
+    }
+}
+
+impl<SM> FailingCase for ParallelCommandSequence<SM>
+where
+    SM: StateMachine + Debug,
+{
+    fn is_empty(&self) -> bool {
+        ParallelCommandSequence::is_empty(self)
+    }
+}
+
+impl<SM> ParallelCommandSequence<SM>
+where
+    SM: StateMachine + Clone + Debug + Sync,
+    SM::Command: Sync,
+    SM::CommandResult: Clone + Send,
+{
+    pub fn run(
+        &mut self,
+        system_under_test: &dyn ConcurrentSystemUnderTest<SM::Command, SM::CommandResult>,
+    ) -> Result<()> {
+        let mut steps = Steps::new(&mut self.state_machine);
+        for (step, (var, cmd)) in self.prefix.iter().enumerate() {
+            let concrete_cmd = steps.resolve(cmd)?;
+            let result = system_under_test.run(&concrete_cmd)?;
+            steps.check(step, *var, cmd, result)?;
+        }
+        let environment = steps.environment;
+
+        let clock = AtomicUsize::new(0);
+        let state_machine = &self.state_machine;
+        let histories = std::thread::scope(|scope| {
+            let handles = self
+                .branches
+                .iter()
+                .map(|branch| {
+                    let mut environment = environment.clone();
+                    let clock = &clock;
+                    scope.spawn(move || -> std::result::Result<_, BranchError> {
+                        let mut history = Vec::with_capacity(branch.len());
+                        for (var, cmd) in branch {
+                            let concrete_cmd = state_machine.resolve(cmd, &environment)?;
+                            let invoked = clock.fetch_add(1, Ordering::SeqCst);
+                            let result = system_under_test.run(&concrete_cmd)?;
+                            let returned = clock.fetch_add(1, Ordering::SeqCst);
+                            environment.bind(*var, result.clone());
+                            history.push(Event {
+                                result,
+                                invoked,
+                                returned,
+                            });
+                        }
+                        Ok(history)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                        .map_err(Error::system_under_test)
+                })
+                .collect::<Result<Vec<Vec<Event<SM::CommandResult>>>>>()
+        })?;
+
+        let mut positions = vec![0; self.branches.len()];
+        if !linearizable(
+            &self.state_machine,
+            &self.branches,
+            &histories,
+            &mut positions,
+        ) {
+            return Err(Error::linearizability(format_history(
+                &self.branches,
+                &histories,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Search for an ordering of the concurrent histories which respects the order of the
+/// commands in each branch, as well as the real-time order of non-overlapping commands,
+/// and for which all the postconditions and invariants of the model hold
+fn linearizable<SM>(
+    state_machine: &SM,
+    branches: &[Vec<(Var, SM::Command)>],
+    histories: &[Vec<Event<SM::CommandResult>>],
+    positions: &mut [usize],
+) -> bool
+where
+    SM: StateMachine + Clone,
+{
+    let pending = (0..branches.len())
+        .filter(|&b| positions[b] < branches[b].len())
+        .collect::<Vec<_>>();
+    let min_returned = match pending
+        .iter()
+        .map(|&b| histories[b][positions[b]].returned)
+        .min()
+    {
+        Some(returned) => returned,
+        None => return true,
+    };
+
+    for b in pending {
+        let event = &histories[b][positions[b]];
+        // A command cannot take effect before a command which returned before it was invoked
+        if event.invoked > min_returned {
+            continue;
+        }
+        let (var, ref cmd) = branches[b][positions[b]];
+        if state_machine.postcondition(cmd, &event.result).is_err() {
+            continue;
+        }
+        let mut next_state_machine = state_machine.clone();
+        next_state_machine.next_state_symbolic(cmd, var);
+        if next_state_machine.invariant().is_err() {
+            continue;
+        }
+        positions[b] += 1;
+        let found = linearizable(&next_state_machine, branches, histories, positions);
+        positions[b] -= 1;
+        if found {
+            return true;
+        }
+    }
+    false
+}
+
+fn format_history<C, R>(branches: &[Vec<(Var, C)>], histories: &[Vec<Event<R>>]) -> String
+where
+    C: Debug,
+    R: Debug,
+{
+    branches
+        .iter()
+        .zip(histories)
+        .enumerate()
+        .map(|(b, (branch, history))| {
+            let events = branch
+                .iter()
+                .zip(history)
+                .map(|((_, cmd), event)| {
+                    format!(
+                        "{:?} => {:?} ({}..{})",
+                        cmd, event.result, event.invoked, event.returned
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("branch {}: [{}]", b, events.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+pub struct ParallelCommandSequenceValueTree<SM>
+where
+    SM: StateMachine,
+{
+    elements: Vec<Box<dyn ValueTree<Value = SM::Command>>>,
+    // Index of the group of each element: 0 for the prefix, b + 1 for branch b
+    groups: Vec<usize>,
+    num_branches: usize,
+    included: Vec<bool>,
+    state_machine: SM,
+    // Minimum number of commands kept when deleting commands
+    min_size: usize,
+    deletion: Deletion,
+    // Elements removed by the last deletion, until it is kept or undone
+    deleted: Vec<usize>,
+}
+
+type Groups<C> = (Vec<(Var, C)>, Vec<Vec<(Var, C)>>);
+
+impl<SM> ParallelCommandSequenceValueTree<SM>
+where
+    SM: StateMachine + Clone + Debug,
+{
+    /// Check whether the included commands are fewer than the lower bound of the
+    /// shrinker
+    fn below_min_size(&self) -> bool {
+        self.included.iter().filter(|&x| *x).count() < self.min_size
+    }
+
+    fn included_indices(&self) -> Vec<usize> {
+        (0..self.elements.len())
+            .filter(|&x| self.included[x])
+            .collect()
+    }
+
+    fn included_commands(&self) -> Groups<SM::Command> {
+        let mut prefix = Vec::new();
+        let mut branches = vec![Vec::new(); self.num_branches];
+        for (index, element) in self.elements.iter().enumerate() {
+            if !self.included[index] {
+                continue;
+            }
+            let entry = (Var(index), element.current());
+            match self.groups[index] {
+                0 => prefix.push(entry),
+                group => branches[group - 1].push(entry),
+            }
+        }
+        (prefix, branches)
+    }
+
+    /// Check that the prefix is valid and that the branches are valid in every
+    /// possible interleaving
+    fn is_valid(&self) -> bool {
+        let (prefix, branches) = self.included_commands();
+        let mut state_machine = self.state_machine.clone();
+        state_machine.reset();
+        let mut bound = HashSet::new();
+        for (var, cmd) in &prefix {
+            if !applicable(&state_machine, &bound, cmd) {
+                return false;
+            }
+            state_machine.next_state_symbolic(cmd, *var);
+            if state_machine.invariant().is_err() {
+                return false;
+            }
+            bound.insert(*var);
+        }
+        let mut positions = vec![0; branches.len()];
+        interleavings_valid(
+            &state_machine,
+            &bound,
+            &branches,
+            &mut positions,
+            &mut HashSet::new(),
+        )
+    }
+}
+
+/// Check that the remaining commands of the branches are valid in every interleaving.
+/// Interleavings which reach the same positions in the same model state share their
+/// continuations, so the states already checked, keyed on the `Debug` representation
+/// of the model as in `run_exhaustive`, are not checked again.
+fn interleavings_valid<SM>(
+    state_machine: &SM,
+    bound: &HashSet<Var>,
+    branches: &[Vec<(Var, SM::Command)>],
+    positions: &mut [usize],
+    checked: &mut HashSet<(Vec<usize>, String)>,
+) -> bool
+where
+    SM: StateMachine + Clone + Debug,
+{
+    if !checked.insert((positions.to_vec(), format!("{:?}", state_machine))) {
+        return true;
+    }
+    for b in 0..branches.len() {
+        if positions[b] == branches[b].len() {
+            continue;
+        }
+        let (var, ref cmd) = branches[b][positions[b]];
+        if !applicable(state_machine, bound, cmd) {
+            return false;
+        }
+        let mut next_state_machine = state_machine.clone();
+        next_state_machine.next_state_symbolic(cmd, var);
+        if next_state_machine.invariant().is_err() {
+            return false;
+        }
+        let mut next_bound = bound.clone();
+        next_bound.insert(var);
+        positions[b] += 1;
+        let valid = interleavings_valid(
+            &next_state_machine,
+            &next_bound,
+            branches,
+            positions,
+            checked,
+        );
+        positions[b] -= 1;
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+impl<SM> ValueTree for ParallelCommandSequenceValueTree<SM>
+where
+    SM: StateMachine + Clone + Debug,
+{
+    type Value = ParallelCommandSequence<SM>;
+
+    fn current(&self) -> Self::Value {
+        let (prefix, branches) = self.included_commands();
+        ParallelCommandSequence {
+            prefix,
+            branches,
+            state_machine: self.state_machine.clone(),
+        }
+    }
+
+    fn simplify(&mut self) -> bool {
+        // The previous deletion was kept, since it was not undone by `complicate`
+        if !std::mem::take(&mut self.deleted).is_empty() {
+            self.deletion.kept();
+        }
+
+        // Remove commands from the prefix and from the branches, skipping removals
+        // which would leave fewer commands than the lower bound of the shrinker or
+        // make the remaining commands invalid
+        loop {
+            let included = self.included_indices();
+            let chunk = match self.deletion.next(included.len()) {
+                Some(chunk) => chunk,
+                None => return false,
+            };
+            self.deleted = included[chunk].to_vec();
+            for &index in &self.deleted {
+                self.included[index] = false;
+            }
+            if !self.below_min_size() && self.is_valid() {
+                return true;
+            }
+            for &index in &std::mem::take(&mut self.deleted) {
+                self.included[index] = true;
+            }
+            self.deletion.undone();
+        }
+    }
+
+    fn complicate(&mut self) -> bool {
+        if self.deleted.is_empty() {
+            return false;
+        }
+        for &index in &std::mem::take(&mut self.deleted) {
+            self.included[index] = true;
+        }
+        self.deletion.undone();
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct ParallelCommandSequenceStrategy<SM>
+where
+    SM: StateMachine + Clone,
+{
+    state_machine: SM,
+    min_size: usize,
+    max_size: usize,
+    num_branches: usize,
+    max_branch_size: usize,
+    stop_without_commands: bool,
+    shrinker: Shrinker,
+    min_shrink_size: usize,
+    // Error of the library which made the generation of a command sequence fail
+    generation_error: RefCell<Option<Error>>,
+}
+
+impl<SM> ParallelCommandSequenceStrategy<SM>
+where
+    SM: StateMachine + Clone,
+{
+    fn new(config: &Config, state_machine: SM) -> Self {
+        assert!(config.max_sequence_size >= config.min_sequence_size);
+        ParallelCommandSequenceStrategy {
+            state_machine,
+            min_size: config.min_sequence_size,
+            max_size: config.max_sequence_size,
+            num_branches: config.parallel_branches,
+            max_branch_size: config.max_branch_size,
+            stop_without_commands: config.stop_without_commands,
+            shrinker: config.shrinker,
+            min_shrink_size: config.min_shrink_size,
+            generation_error: RefCell::new(None),
+        }
+    }
+}
+
+impl<SM> Strategy for ParallelCommandSequenceStrategy<SM>
+where
+    SM: StateMachine + Clone + Debug,
+{
+    type Tree = ParallelCommandSequenceValueTree<SM>;
+    type Value = ParallelCommandSequence<SM>;
+
+    fn new_tree(&self, runner: &mut TestRunner) -> NewTree<Self> {
+        let size = (self.min_size..=self.max_size).new_tree(runner)?.current();
+
+        let mut state_machine = self.state_machine.clone();
+        state_machine.reset();
+        let mut elements = Vec::new();
+        generate_commands(
+            runner,
+            &mut state_machine,
+            size,
+            self.stop_without_commands,
+            &self.generation_error,
+            &mut elements,
+        )?;
+        let prefix_size = elements.len();
+
+        loop {
+            elements.truncate(prefix_size);
+            let mut groups = vec![0; prefix_size];
+            for b in 0..self.num_branches {
+                let branch_size = (0..=self.max_branch_size).new_tree(runner)?.current();
+                let mut branch_state_machine = state_machine.clone();
+                generate_commands(
+                    runner,
+                    &mut branch_state_machine,
+                    branch_size,
+                    self.stop_without_commands,
+                    &self.generation_error,
+                    &mut elements,
+                )?;
+                groups.resize(elements.len(), b + 1);
+            }
+
+            let num_elements = elements.len();
+            let mut tree_state_machine = self.state_machine.clone();
+            tree_state_machine.reset();
+            let tree = ParallelCommandSequenceValueTree {
+                elements,
+                groups,
+                num_branches: self.num_branches,
+                included: vec![true; num_elements],
+                state_machine: tree_state_machine,
+                min_size: self.min_shrink_size,
+                deletion: Deletion::new(self.shrinker, num_elements),
+                deleted: Vec::new(),
+            };
+            if tree.is_valid() {
+                return Ok(tree);
+            }
+            runner.reject_local("Branches are not valid in every interleaving")?;
+            elements = tree.elements;
+        }
+    }
+}
+
+fn parallel_command_sequence<SM>(
+    config: &Config,
+    state_machine: SM,
+) -> ParallelCommandSequenceStrategy<SM>
+where
+    SM: StateMachine + Clone,
+{
+    ParallelCommandSequenceStrategy::new(config, state_machine)
+}
+
+/// Run a set of parallel tests for the provided concurrent system-under-test, using
+/// the given state machine model and configuration parameters. Each test case runs
+/// a sequential prefix of commands followed by several branches of commands, which
+/// are executed concurrently, and checks that the resulting history is linearizable
+/// with respect to the model.
+pub fn run_parallel<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<ParallelCommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug + Sync,
+    SM::Command: Sync,
+    SM::CommandResult: Clone + Send,
+    SUTF: Fn() -> Box<dyn ConcurrentSystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+
+    let strategy = parallel_command_sequence(&config, state_machine);
+    let result = runner.run(&strategy, |mut commands| {
+        let sys = system_under_test_factory();
+        commands.run(sys.as_ref())?;
+        Ok(())
+    });
+    report_failure(result, strategy.generation_error.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use proptest::strategy::{Strategy, ValueTree};
+    use proptest::test_runner::TestRunner;
+
+    use super::parallel_command_sequence;
+    use crate::test_models::{Counter, CounterCommand, CounterModel, StackModel};
+    use crate::{run_parallel, ConcurrentSystemUnderTest, Config, Result, TestFailure};
+
+    // Increments the counter with a separate load and store, losing concurrent updates
+    struct RacyCounter {
+        value: AtomicUsize,
+    }
+
+    impl ConcurrentSystemUnderTest<CounterCommand, usize> for RacyCounter {
+        fn run(&self, _cmd: &CounterCommand) -> Result<usize> {
+            let value = self.value.load(Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            self.value.store(value + 1, Ordering::SeqCst);
+            Ok(value + 1)
+        }
+    }
+
+    fn config() -> Config {
+        let mut config = Config {
+            min_sequence_size: 0,
+            max_sequence_size: 3,
+            min_shrink_size: 0,
+            ..Config::default()
+        };
+        config.proptest.cases = 32;
+        config.proptest.max_shrink_iters = 100;
+        config
+    }
+
+    #[test]
+    fn parallel_detects_race() {
+        let result = run_parallel(config(), CounterModel { value: 0 }, || {
+            Box::new(RacyCounter {
+                value: AtomicUsize::new(0),
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(..)) => {}
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[test]
+    fn parallel_reports_failure_before_any_command() {
+        let result = run_parallel(
+            config(),
+            CounterModel { value: 0 },
+            || -> Box<dyn ConcurrentSystemUnderTest<CounterCommand, usize>> {
+                panic!("Could not build the counter")
+            },
+        );
+        match result {
+            Err(TestFailure::BeforeAnyCommand(_)) => {}
+            _ => panic!("Test should have failed before any command"),
+        }
+    }
+
+    #[test]
+    fn parallel_accepts_serialized_system() {
+        let result = run_parallel(config(), CounterModel { value: 0 }, || {
+            Box::new(Mutex::new(Counter { value: 0 }))
+        });
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn shrink_revisits_commands_needed_by_later_ones() {
+        let mut config = config();
+        config.max_sequence_size = 10;
+        let strategy = parallel_command_sequence(&config, StackModel { depth: 0 });
+        let mut runner = TestRunner::default();
+        for _ in 0..100 {
+            // Shrink as if every command sequence failed: pushes can only be deleted
+            // once the pops following them were deleted
+            let mut tree = strategy.new_tree(&mut runner).unwrap();
+            while tree.simplify() {}
+            assert!(tree.current().is_empty());
+        }
+    }
+
+    #[test]
+    fn shrink_keeps_min_shrink_size_commands() {
+        let mut config = config();
+        config.min_sequence_size = 3;
+        config.max_sequence_size = 10;
+        config.min_shrink_size = 2;
+        let strategy = parallel_command_sequence(&config, CounterModel { value: 0 });
+        let mut runner = TestRunner::default();
+        for _ in 0..100 {
+            let mut tree = strategy.new_tree(&mut runner).unwrap();
+            while tree.simplify() {}
+            let current = tree.current();
+            let len = current.prefix.len() + current.branches.iter().map(Vec::len).sum::<usize>();
+            assert_eq!(len, 2);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{applicable, CommandSequence, Config, StateMachine, SystemUnderTest, TestFailure, Var};
+
+/// Serialized form of a failing command sequence, stored as one line of JSON in the
+/// regressions file
+#[derive(Serialize, Deserialize)]
+struct PersistedSequence<C> {
+    variables: Vec<Var>,
+    commands: Vec<C>,
+}
+
+fn regressions_file(config: &Config) -> Option<PathBuf> {
+    config.regressions_file.clone().or_else(|| {
+        config
+            .proptest
+            .source_file
+            .map(|source_file| Path::new(source_file).with_extension("sequences"))
+    })
+}
+
+fn store_sequence<C>(path: &Path, sequence: &PersistedSequence<C>) -> std::io::Result<()>
+where
+    C: Serialize,
+{
+    let line = serde_json::to_string(sequence)?;
+    if let Ok(contents) = fs::read_to_string(path) {
+        if contents.lines().any(|l| l == line) {
+            return Ok(());
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// Decode a persisted sequence, checking that it can still be produced by the model,
+/// which may have evolved since the sequence was stored
+fn decode<SM>(state_machine: &SM, line: &str) -> Option<CommandSequence<SM>>
+where
+    SM: StateMachine + Clone,
+    SM::Command: DeserializeOwned,
+{
+    let sequence: PersistedSequence<SM::Command> = serde_json::from_str(line).ok()?;
+    if sequence.variables.len() != sequence.commands.len() {
+        return None;
+    }
+    let mut model = state_machine.clone();
+    model.reset();
+    let mut bound = HashSet::new();
+    let mut commands = Vec::with_capacity(sequence.commands.len());
+    for (cmd, var) in sequence.commands.into_iter().zip(sequence.variables) {
+        if !applicable(&model, &bound, &cmd) {
+            return None;
+        }
+        model.next_state_symbolic(&cmd, var);
+        if model.invariant().is_err() {
+            return None;
+        }
+        bound.insert(var);
+        commands.push((var, cmd));
+    }
+    Some(CommandSequence::new(state_machine.clone(), commands))
+}
+
+/// Replay the failing command sequences stored in the regressions file, before any new
+/// sequence is generated, so that they keep being tested even after the command
+/// generation of the model has changed. Stored sequences which the model no longer
+/// accepts are reported and skipped.
+pub(crate) fn replay<SM, SUTF>(
+    config: &Config,
+    state_machine: &SM,
+    system_under_test_factory: &SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug,
+    SM::Command: DeserializeOwned,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let path = match regressions_file(config) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            eprintln!(
+                "Could not read persisted failing cases from {}: {}",
+                path.display(),
+                e
+            );
+            return Ok(());
+        }
+    };
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let mut commands = match decode(state_machine, line) {
+            Some(commands) => commands,
+            None => {
+                eprintln!(
+                    "Skipping command sequence in {} which the model no longer accepts: {}",
+                    path.display(),
+                    line
+                );
+                continue;
+            }
+        };
+        let mut sys = system_under_test_factory();
+        if let Err(e) = commands.run(&mut sys) {
+            return Err(TestFailure::Fail(e.to_string().into(), commands));
+        }
+    }
+    Ok(())
+}
+
+/// Store the minimal failing command sequence of a test run in the regressions file
+pub(crate) fn store<T, SM>(
+    config: &Config,
+    result: &std::result::Result<T, TestFailure<CommandSequence<SM>>>,
+) where
+    SM: StateMachine,
+    SM::Command: Serialize,
+{
+    let (path, commands) = match (regressions_file(config), result) {
+        (Some(path), Err(TestFailure::Fail(_, commands))) => (path, commands),
+        _ => return,
+    };
+    let sequence = PersistedSequence {
+        variables: commands.variables.clone(),
+        commands: commands.commands.clone(),
+    };
+    if let Err(e) = store_sequence(&path, &sequence) {
+        eprintln!(
+            "Could not persist failing case to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use proptest::strategy::{Just, Strategy};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{run, Config, Error, Result, StateMachine, SystemUnderTest, TestFailure};
+
+    #[derive(Clone, Debug)]
+    struct CountingModel {
+        count: usize,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    enum CountingCommand {
+        Tick,
+    }
+
+    impl StateMachine for CountingModel {
+        type Command = CountingCommand;
+
+        type CommandResult = usize;
+
+        fn reset(&mut self) {
+            self.count = 0;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![(1, Just(CountingCommand::Tick).boxed())]
+        }
+
+        // The system-under-test misbehaves on the third command
+        fn postcondition(&self, cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            if self.count == 2 {
+                return Result::Err(Error::postcondition(
+                    format!("{:?}", cmd),
+                    format!("{:?}", 0),
+                    format!("{:?}", 0),
+                ));
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {
+            self.count += 1;
+        }
+    }
+
+    struct CountingSystem;
+
+    impl SystemUnderTest<CountingCommand, usize> for CountingSystem {
+        fn run(&mut self, _cmd: &CountingCommand) -> Result<usize> {
+            Ok(0)
+        }
+    }
+
+    fn regressions_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "proptest-stateful-{}-{}.sequences",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn config(size: usize, path: &Path) -> Config {
+        Config {
+            min_sequence_size: size,
+            max_sequence_size: size,
+            regressions_file: Some(path.to_path_buf()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn failing_sequence_is_persisted_and_replayed() {
+        let path = regressions_file("replayed");
+        let config = |size| config(size, &path);
+
+        let result = run(config(5), CountingModel { count: 0 }, || {
+            Box::new(CountingSystem)
+        });
+        assert!(matches!(result, Err(TestFailure::Fail(..))));
+        let contents = fs::read_to_string(&path).expect("Regressions file not written");
+        assert_eq!(contents.lines().count(), 1);
+
+        // Sequences of a single command cannot fail, so the failure comes from the replay
+        let result = run(config(1), CountingModel { count: 0 }, || {
+            Box::new(CountingSystem)
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => assert_eq!(seq.commands.len(), 3),
+            _ => panic!("Persisted sequence should have failed"),
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    // The stored sequence would fail, but the model no longer has the `Tock` command
+    #[test]
+    fn rejected_sequence_is_skipped() {
+        let path = regressions_file("rejected");
+        fs::write(
+            &path,
+            r#"{"variables":[0,1,2],"commands":["Tick","Tock","Tick"]}"#,
+        )
+        .unwrap();
+
+        let result = run(config(1, &path), CountingModel { count: 0 }, || {
+            Box::new(CountingSystem)
+        });
+        assert!(result.is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    ffi::{OsStr, OsString},
+    io::{self, BufRead, BufReader, Read, Write},
+    marker::PhantomData,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Error, Result, SystemUnderTest};
+
+/// Running instance of the executable
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: Arc<Mutex<String>>,
+    // Drains the standard error of the process, so that the process never blocks on it
+    stderr_reader: JoinHandle<()>,
+}
+
+impl Process {
+    /// Send a request line and read the response line, returning `None` if the process
+    /// closed its standard output
+    fn request(&mut self, request: &str) -> io::Result<Option<String>> {
+        writeln!(self.stdin, "{}", request)?;
+        self.stdin.flush()?;
+        let mut response = String::new();
+        if self.stdout.read_line(&mut response)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(response))
+    }
+
+    /// Terminate the process, returning its exit status and everything it wrote to
+    /// its standard error
+    fn stop(mut self) -> (String, String) {
+        let _ = self.child.kill();
+        let status = match self.child.wait() {
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        };
+        let _ = self.stderr_reader.join();
+        let stderr = self.stderr.lock().map(|s| s.clone()).unwrap_or_default();
+        (status, stderr)
+    }
+}
+
+/// System-under-test implemented by a local executable, such as a service written in
+/// another language. Each command is serialized as a line of JSON written to the
+/// standard input of the process, which answers with the result serialized as a line
+/// of JSON on its standard output. When the process terminates while running a
+/// command, or answers with a line which cannot be decoded, the command fails with
+/// `Error::SystemUnderTest`, which includes the standard error of the process, and the
+/// process is restarted by the next command.
+pub struct ProcessSystem<C, R> {
+    program: OsString,
+    args: Vec<OsString>,
+    process: Option<Process>,
+    _types: PhantomData<fn(&C) -> R>,
+}
+
+impl<C, R> ProcessSystem<C, R> {
+    /// Spawn the executable `program` with the provided arguments
+    pub fn new<P, I, S>(program: P, args: I) -> Result<Self>
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut system = ProcessSystem {
+            program: program.as_ref().to_os_string(),
+            args: args
+                .into_iter()
+                .map(|arg| arg.as_ref().to_os_string())
+                .collect(),
+            process: None,
+            _types: PhantomData,
+        };
+        system.process = Some(system.spawn()?);
+        Ok(system)
+    }
+
+    fn spawn(&self) -> Result<Process> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::system_under_test)?;
+        let stdin = child.stdin.take().expect("Standard input is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("Standard output is piped"));
+        let mut stderr_pipe = child.stderr.take().expect("Standard error is piped");
+        let stderr = Arc::new(Mutex::new(String::new()));
+        let captured = stderr.clone();
+        let stderr_reader = thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok(n) = stderr_pipe.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                if let Ok(mut captured) = captured.lock() {
+                    captured.push_str(&String::from_utf8_lossy(&buffer[..n]));
+                }
+            }
+        });
+        Ok(Process {
+            child,
+            stdin,
+            stdout,
+            stderr,
+            stderr_reader,
+        })
+    }
+}
+
+impl<C, R> SystemUnderTest<C, R> for ProcessSystem<C, R>
+where
+    C: Serialize,
+    R: DeserializeOwned,
+{
+    fn run(&mut self, cmd: &C) -> Result<R> {
+        let request = serde_json::to_string(cmd).map_err(Error::system_under_test)?;
+        if self.process.is_none() {
+            self.process = Some(self.spawn()?);
+        }
+        let process = self.process.as_mut().expect("Process is running");
+        let failure = match process.request(&request) {
+            Ok(Some(response)) => match serde_json::from_str(&response) {
+                Ok(result) => return Ok(result),
+                Err(e) => format!(
+                    "answered command {} with invalid response {} ({})",
+                    request,
+                    response.trim_end(),
+                    e
+                ),
+            },
+            Ok(None) | Err(_) => format!("terminated while running command {}", request),
+        };
+        // The process is out of sync with the harness, so it is restarted by the next command
+        let (status, stderr) = self.process.take().expect("Process is running").stop();
+        Err(Error::system_under_test(io::Error::other(format!(
+            "Process {} {} ({}). Standard error: {}",
+            self.program.to_string_lossy(),
+            failure,
+            status,
+            stderr.trim_end()
+        ))))
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        if let Some(process) = self.process.take() {
+            process.stop();
+        }
+        Ok(())
+    }
+
+    /// Restart the process, so that it starts again from its initial state
+    fn reset(&mut self) -> Result<()> {
+        self.cleanup()?;
+        self.process = Some(self.spawn()?);
+        Ok(())
+    }
+}
+
+impl<C, R> Drop for ProcessSystem<C, R> {
+    fn drop(&mut self) {
+        if let Some(process) = self.process.take() {
+            process.stop();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use proptest::strategy::Strategy;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{run, Config, Error, ProcessSystem, Result, StateMachine, SystemUnderTest};
+
+    // Echoes every line back, except for the `Crash` command, on which it exits, and the
+    // `Garble` command, which it answers with a line which is not JSON
+    const ECHO: &str = r#"while read -r line; do
+        case "$line" in
+            *Crash*) echo "crashed" >&2; exit 1 ;;
+            *Garble*) echo "garbled" >&2; echo "garbled" ; continue ;;
+        esac
+        echo "$line"
+    done"#;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    enum EchoCommand {
+        Echo(u32),
+        Crash,
+        Garble,
+    }
+
+    #[derive(Clone, Debug)]
+    struct EchoModel;
+
+    impl StateMachine for EchoModel {
+        type Command = EchoCommand;
+
+        type CommandResult = EchoCommand;
+
+        fn reset(&mut self) {}
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![(1, (0..1000u32).prop_map(EchoCommand::Echo).boxed())]
+        }
+
+        fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+            if res != cmd {
+                return Err(Error::postcondition(
+                    format!("{:?}", cmd),
+                    format!("{:?}", cmd),
+                    format!("{:?}", res),
+                ));
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {}
+    }
+
+    fn echo() -> ProcessSystem<EchoCommand, EchoCommand> {
+        ProcessSystem::new("sh", ["-c", ECHO]).expect("Could not spawn the echo program")
+    }
+
+    #[test]
+    fn commands_are_exchanged_as_json_lines() {
+        let mut config = Config {
+            max_sequence_size: 20,
+            ..Config::default()
+        };
+        config.proptest.cases = 16;
+        let result = run(config, EchoModel, || Box::new(echo()));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn crashed_process_is_restarted() {
+        let mut sys = echo();
+        assert_eq!(
+            sys.run(&EchoCommand::Echo(1)).unwrap(),
+            EchoCommand::Echo(1)
+        );
+        match sys.run(&EchoCommand::Crash) {
+            Err(e @ Error::SystemUnderTest { .. }) => {
+                assert!(e.to_string().ends_with("Standard error: crashed"))
+            }
+            _ => panic!("Command should have failed"),
+        }
+        assert_eq!(
+            sys.run(&EchoCommand::Echo(2)).unwrap(),
+            EchoCommand::Echo(2)
+        );
+    }
+
+    #[test]
+    fn process_with_invalid_response_is_restarted() {
+        let mut sys = echo();
+        match sys.run(&EchoCommand::Garble) {
+            Err(e @ Error::SystemUnderTest { .. }) => {
+                let message = e.to_string();
+                assert!(message.contains("invalid response garbled"));
+                assert!(message.ends_with("Standard error: garbled"));
+            }
+            _ => panic!("Command should have failed"),
+        }
+        assert_eq!(
+            sys.run(&EchoCommand::Echo(1)).unwrap(),
+            EchoCommand::Echo(1)
+        );
+    }
+}
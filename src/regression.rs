@@ -0,0 +1,61 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{CommandSequence, StateMachine};
+
+impl<SM> CommandSequence<SM>
+where
+    SM: StateMachine,
+{
+    /// Returns the source code of a standalone `#[test]` function which replays the
+    /// command sequence, so that a minimal failing case can be kept as a deterministic
+    /// regression test. The test is named `name`; `state_machine` and
+    /// `system_under_test` are Rust expressions constructing the model and the boxed
+    /// system-under-test, and `format_command` renders each command as a Rust expression.
+    pub fn to_regression_test<F>(
+        &self,
+        name: &str,
+        state_machine: &str,
+        system_under_test: &str,
+        format_command: F,
+    ) -> String
+    where
+        F: Fn(&SM::Command) -> String,
+    {
+        let steps = self
+            .variables
+            .iter()
+            .zip(&self.commands)
+            .map(|(var, cmd)| {
+                format!(
+                    "            (proptest_stateful::Var({}), {}),\n",
+                    var.0,
+                    format_command(cmd)
+                )
+            })
+            .collect::<String>();
+        format!(
+            concat!(
+                "#[test]\n",
+                "fn {name}() {{\n",
+                "    let mut commands = proptest_stateful::CommandSequence::new(\n",
+                "        {state_machine},\n",
+                "        vec![\n",
+                "{steps}",
+                "        ],\n",
+                "    );\n",
+                "    let mut sys: Box<dyn proptest_stateful::SystemUnderTest<_, _>> = {system_under_test};\n",
+                "    commands.run(&mut sys).unwrap_or_else(|e| panic!(\"{{}}\", e));\n",
+                "}}\n"
+            ),
+            name = name,
+            state_machine = state_machine,
+            steps = steps,
+            system_under_test = system_under_test
+        )
+    }
+}
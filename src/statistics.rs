@@ -0,0 +1,218 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use crate::{CommandSequence, StateMachine};
+
+thread_local! {
+    static LABELS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Attach a label to the command sequence which is currently being run. Labels are
+/// typically emitted from `StateMachine::next_state` to record that an interesting
+/// state was reached, and are aggregated in the `Statistics` of the test run. Calls
+/// made while no command sequence is being run are ignored.
+pub fn label<T: AsRef<str>>(label: T) {
+    LABELS.with(|labels| {
+        if let Some(labels) = labels.borrow_mut().as_mut() {
+            labels.push(label.as_ref().to_string());
+        }
+    })
+}
+
+/// Collect the labels emitted while running `f`
+pub(crate) fn collect_labels<T, F>(f: F) -> (T, Vec<String>)
+where
+    F: FnOnce() -> T,
+{
+    LABELS.with(|labels| *labels.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let labels = LABELS.with(|labels| labels.borrow_mut().take().unwrap_or_default());
+    (result, labels)
+}
+
+/// Aggregated statistics of the command sequences run during a test
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Number of command sequences which were run
+    pub num_sequences: usize,
+
+    /// Number of times each kind of command was run, indexed by command name
+    pub commands: BTreeMap<String, usize>,
+
+    /// Number of command sequences of each length
+    pub sequence_lengths: BTreeMap<usize, usize>,
+
+    /// Number of command sequences in which each label was emitted
+    pub labels: BTreeMap<String, usize>,
+}
+
+impl Statistics {
+    pub(crate) fn record<SM>(&mut self, sequence: &CommandSequence<SM>, labels: Vec<String>)
+    where
+        SM: StateMachine,
+    {
+        self.num_sequences += 1;
+        for cmd in &sequence.commands {
+            *self
+                .commands
+                .entry(sequence.state_machine.command_name(cmd))
+                .or_insert(0) += 1;
+        }
+        *self
+            .sequence_lengths
+            .entry(sequence.commands.len())
+            .or_insert(0) += 1;
+        for label in labels.into_iter().collect::<BTreeSet<_>>() {
+            *self.labels.entry(label).or_insert(0) += 1;
+        }
+    }
+}
+
+fn percentage(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+impl std::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let num_commands = self.commands.values().sum::<usize>();
+        writeln!(
+            f,
+            "{} command sequences, {} commands",
+            self.num_sequences, num_commands
+        )?;
+
+        writeln!(f, "Commands:")?;
+        for (name, count) in &self.commands {
+            writeln!(
+                f,
+                "  {:6.2}% {} ({})",
+                percentage(*count, num_commands),
+                name,
+                count
+            )?;
+        }
+
+        writeln!(f, "Sequence lengths:")?;
+        let mut buckets = BTreeMap::new();
+        for (length, count) in &self.sequence_lengths {
+            *buckets.entry(length / 10).or_insert(0) += count;
+        }
+        for (bucket, count) in buckets {
+            writeln!(
+                f,
+                "  {:6.2}% {}-{}",
+                percentage(count, self.num_sequences),
+                bucket * 10,
+                bucket * 10 + 9
+            )?;
+        }
+
+        if !self.labels.is_empty() {
+            writeln!(f, "Labels:")?;
+            for (label, count) in &self.labels {
+                writeln!(
+                    f,
+                    "  {:6.2}% {}",
+                    percentage(*count, self.num_sequences),
+                    label
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::strategy::{Just, Strategy};
+
+    use crate::{label, run_with_statistics, Config, Result, StateMachine, SystemUnderTest};
+
+    #[derive(Clone, Debug)]
+    struct SwitchModel {
+        on: bool,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    enum SwitchCommand {
+        Toggle,
+        Read { twice: bool },
+    }
+
+    impl StateMachine for SwitchModel {
+        type Command = SwitchCommand;
+
+        type CommandResult = bool;
+
+        fn reset(&mut self) {
+            self.on = false;
+        }
+
+        fn commands(&self) -> Vec<(usize, proptest::strategy::BoxedStrategy<Self::Command>)> {
+            vec![
+                (1, Just(SwitchCommand::Toggle).boxed()),
+                (1, Just(SwitchCommand::Read { twice: false }).boxed()),
+            ]
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &Self::Command) {
+            if let SwitchCommand::Toggle = cmd {
+                self.on = !self.on;
+                label(if self.on { "on" } else { "off" });
+            }
+        }
+    }
+
+    struct Switch;
+
+    impl SystemUnderTest<SwitchCommand, bool> for Switch {
+        fn run(&mut self, cmd: &SwitchCommand) -> Result<bool> {
+            match *cmd {
+                SwitchCommand::Toggle => Ok(true),
+                SwitchCommand::Read { twice } => Ok(twice),
+            }
+        }
+    }
+
+    #[test]
+    fn statistics_are_aggregated() {
+        let mut config = Config {
+            min_sequence_size: 5,
+            max_sequence_size: 5,
+            ..Config::default()
+        };
+        config.proptest.cases = 10;
+        let statistics =
+            run_with_statistics(config, SwitchModel { on: false }, || Box::new(Switch))
+                .expect("Test should have passed");
+
+        assert_eq!(statistics.num_sequences, 10);
+        assert_eq!(statistics.commands.values().sum::<usize>(), 50);
+        assert_eq!(statistics.commands.len(), 2);
+        assert!(statistics.commands.contains_key("Toggle"));
+        assert!(statistics.commands.contains_key("Read"));
+        assert_eq!(statistics.sequence_lengths.get(&5), Some(&10));
+        assert!(statistics.labels.values().all(|count| *count <= 10));
+        assert_eq!(statistics.labels.len(), 2);
+        assert!(statistics.labels.contains_key("on"));
+        assert!(statistics.labels.contains_key("off"));
+    }
+}
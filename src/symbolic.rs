@@ -0,0 +1,83 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use crate::errors::{Error, Result};
+
+/// Symbolic variable standing for the result of a command in a command sequence.
+/// Variables are assigned by the library when the command sequence is generated and
+/// are only bound to concrete values when the sequence is run against the
+/// system-under-test. The index is public so that command sequences can be written
+/// by hand, for example in regression tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Var(pub usize);
+
+impl std::fmt::Display for Var {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "var{}", self.0)
+    }
+}
+
+/// Command argument which is either a symbolic variable, as seen by the model, or
+/// the concrete value the variable is bound to, as seen by the system-under-test
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Symbolic<T> {
+    Var(Var),
+    Value(T),
+}
+
+impl<T> Symbolic<T> {
+    /// Returns the symbolic variable, if the argument has not been resolved yet
+    pub fn var(&self) -> Option<Var> {
+        match *self {
+            Symbolic::Var(var) => Some(var),
+            Symbolic::Value(_) => None,
+        }
+    }
+
+    /// Returns the concrete value, if the argument has been resolved
+    pub fn value(&self) -> Option<&T> {
+        match *self {
+            Symbolic::Var(_) => None,
+            Symbolic::Value(ref value) => Some(value),
+        }
+    }
+}
+
+impl<T> From<Var> for Symbolic<T> {
+    fn from(var: Var) -> Self {
+        Symbolic::Var(var)
+    }
+}
+
+/// Bindings of symbolic variables to the results returned by the system-under-test
+#[derive(Clone, Debug)]
+pub struct Environment<R> {
+    bindings: HashMap<Var, R>,
+}
+
+impl<R> Environment<R> {
+    pub(crate) fn new() -> Self {
+        Environment {
+            bindings: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn bind(&mut self, var: Var, value: R) {
+        self.bindings.insert(var, value);
+    }
+
+    /// Returns the value bound to the symbolic variable
+    pub fn get(&self, var: Var) -> Result<&R> {
+        self.bindings
+            .get(&var)
+            .ok_or_else(|| Error::unbound_variable(var))
+    }
+}
@@ -0,0 +1,370 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    rc::Rc,
+};
+
+use proptest::{
+    strategy::{BoxedStrategy, NewTree, Strategy, ValueTree},
+    test_runner::{Reason, TestRunner},
+};
+use rand::distr::{Distribution, Uniform};
+
+use crate::{
+    applicable, command_sequence, generate_commands, report_failure, CommandSequence,
+    CommandSequenceStrategy, CommandSequenceValueTree, Config, StateMachine, SystemUnderTest,
+    TestFailure, Var,
+};
+
+thread_local! {
+    static UTILITY: RefCell<Option<f64>> = const { RefCell::new(None) };
+}
+
+/// Report the utility of the command sequence which is currently being run, for
+/// example the depth of a queue after a command. The utility of a command sequence is
+/// the maximum value reported while running it, and `run_targeted` searches for the
+/// command sequences with the highest utility. Calls made while no command sequence
+/// is being run by `run_targeted` are ignored.
+pub fn target(utility: f64) {
+    UTILITY.with(|max| {
+        if let Some(max) = max.borrow_mut().as_mut() {
+            *max = max.max(utility);
+        }
+    })
+}
+
+/// Collect the maximum utility reported while running `f`
+fn collect_utility<T, F>(f: F) -> (T, f64)
+where
+    F: FnOnce() -> T,
+{
+    UTILITY.with(|max| *max.borrow_mut() = Some(f64::NEG_INFINITY));
+    let result = f();
+    let utility = UTILITY.with(|max| max.borrow_mut().take().unwrap_or(f64::NEG_INFINITY));
+    (result, utility)
+}
+
+/// Value tree of a generated command, shared between the command sequences which
+/// hold the command, so that the neighbours of an accepted command sequence can still
+/// simplify the commands they copy from it. Only the value trees of a failing command
+/// sequence are simplified, after which no neighbour is generated.
+struct SharedTree<C>(Rc<RefCell<Box<dyn ValueTree<Value = C>>>>);
+
+impl<C> Clone for SharedTree<C> {
+    fn clone(&self) -> Self {
+        SharedTree(Rc::clone(&self.0))
+    }
+}
+
+impl<C: Debug> Debug for SharedTree<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.current().fmt(f)
+    }
+}
+
+impl<C: Debug> ValueTree for SharedTree<C> {
+    type Value = C;
+
+    fn current(&self) -> C {
+        self.0.borrow().current()
+    }
+
+    fn simplify(&mut self) -> bool {
+        self.0.borrow_mut().simplify()
+    }
+
+    fn complicate(&mut self) -> bool {
+        self.0.borrow_mut().complicate()
+    }
+}
+
+/// Returns the value trees of the commands, as expected by the value tree of a command
+/// sequence
+fn boxed<C>(commands: &[SharedTree<C>]) -> Vec<Box<dyn ValueTree<Value = C>>>
+where
+    C: Debug + 'static,
+{
+    commands
+        .iter()
+        .map(|tree| Box::new(tree.clone()) as Box<dyn ValueTree<Value = C>>)
+        .collect()
+}
+
+/// Command sequence which passed, along with its utility
+#[derive(Debug)]
+struct Candidate<C> {
+    commands: Vec<SharedTree<C>>,
+    utility: f64,
+}
+
+/// Strategy which generates each command sequence as a neighbour of the command
+/// sequence accepted by a simulated annealing search: a random command of the
+/// accepted sequence is generated anew, the following commands are kept while they
+/// remain applicable, and the sequence is completed with new commands
+#[derive(Debug)]
+struct TargetedStrategy<SM>
+where
+    SM: StateMachine + Clone,
+{
+    base: CommandSequenceStrategy<BoxedStrategy<SM::Command>, SM>,
+    initial_temperature: f64,
+    num_cases: usize,
+    num_generated: Cell<usize>,
+    accepted: RefCell<Option<Candidate<SM::Command>>>,
+    // Commands of the last generated command sequence
+    generated: RefCell<Vec<SharedTree<SM::Command>>>,
+    // Last command sequence which passed, waiting to be accepted or rejected
+    evaluated: RefCell<Option<Candidate<SM::Command>>>,
+}
+
+impl<SM> TargetedStrategy<SM>
+where
+    SM: StateMachine + Clone + Debug,
+{
+    fn new(config: &Config, state_machine: SM) -> Self {
+        TargetedStrategy {
+            base: command_sequence(config, state_machine),
+            initial_temperature: config.targeted_temperature,
+            num_cases: config.proptest.cases as usize,
+            num_generated: Cell::new(0),
+            accepted: RefCell::new(None),
+            generated: RefCell::new(Vec::new()),
+            evaluated: RefCell::new(None),
+        }
+    }
+
+    /// Record the last generated command sequence as passed, with the provided utility
+    fn evaluated(&self, utility: f64) {
+        *self.evaluated.borrow_mut() = Some(Candidate {
+            commands: self.generated.take(),
+            utility,
+        });
+    }
+
+    /// Decide whether the last command sequence which passed replaces the accepted one.
+    /// Better sequences are always accepted, while worse sequences are accepted with a
+    /// probability which decreases with the temperature.
+    fn accept(&self, runner: &mut TestRunner) -> std::result::Result<(), Reason> {
+        let evaluated = match self.evaluated.borrow_mut().take() {
+            Some(evaluated) => evaluated,
+            None => return Ok(()),
+        };
+        let mut accepted = self.accepted.borrow_mut();
+        let accept = match *accepted {
+            None => true,
+            Some(ref current) if evaluated.utility >= current.utility => true,
+            Some(ref current) => {
+                let progress = self.num_generated.get() as f64 / self.num_cases.max(1) as f64;
+                let temperature = self.initial_temperature * (1.0 - progress).max(0.0);
+                temperature > 0.0
+                    && (0.0..1.0f64).new_tree(runner)?.current()
+                        < ((evaluated.utility - current.utility) / temperature).exp()
+            }
+        };
+        if accept {
+            *accepted = Some(evaluated);
+        }
+        Ok(())
+    }
+
+    /// Generate `size` commands from the current state of the model and append them,
+    /// as shared value trees, to `commands`
+    fn generate(
+        &self,
+        runner: &mut TestRunner,
+        state_machine: &mut SM,
+        size: usize,
+        commands: &mut Vec<SharedTree<SM::Command>>,
+    ) -> std::result::Result<(), Reason>
+    where
+        SM::Command: 'static,
+    {
+        // The commands already in place are passed along, since the variables bound by
+        // the new commands are given by their position in the sequence
+        let start = commands.len();
+        let mut elements = boxed(commands);
+        generate_commands(
+            runner,
+            state_machine,
+            size,
+            self.base.stop_without_commands,
+            &self.base.generation_error,
+            &mut elements,
+        )?;
+        commands.extend(
+            elements
+                .drain(start..)
+                .map(|tree| SharedTree(Rc::new(RefCell::new(tree)))),
+        );
+        Ok(())
+    }
+}
+
+impl<SM> Strategy for TargetedStrategy<SM>
+where
+    SM: StateMachine + Clone + Debug,
+    SM::Command: 'static,
+{
+    type Tree = CommandSequenceValueTree<SM>;
+    type Value = CommandSequence<SM>;
+
+    fn new_tree(&self, runner: &mut TestRunner) -> NewTree<Self> {
+        self.accept(runner)?;
+        self.num_generated.set(self.num_generated.get() + 1);
+
+        // The first command sequence is generated from scratch, as if the accepted
+        // command sequence was empty
+        let accepted = self.accepted.borrow();
+        let current = accepted
+            .as_ref()
+            .map_or(&[][..], |current| &current.commands[..]);
+
+        let size = Uniform::new_inclusive(self.base.min_size, self.base.next_max_size())
+            .map_err(|e| e.to_string())?
+            .sample(runner.rng());
+        let index = (0..size.max(1)).new_tree(runner)?.current();
+        let mut state_machine = self.base.state_machine.clone();
+        state_machine.reset();
+        let mut commands = Vec::with_capacity(size);
+        for tree in current.iter().take(index) {
+            state_machine.next_state_symbolic(&tree.current(), Var(commands.len()));
+            commands.push(tree.clone());
+        }
+        let prefix = commands.len();
+        self.generate(runner, &mut state_machine, 1, &mut commands)?;
+        // The commands following the replaced one keep their position, and thereby the
+        // variables they reference, as long as they remain applicable
+        if commands.len() == prefix + 1 {
+            for tree in current.iter().skip(prefix + 1) {
+                let cmd = tree.current();
+                let bound = (0..commands.len()).map(Var).collect();
+                if commands.len() >= size || !applicable(&state_machine, &bound, &cmd) {
+                    break;
+                }
+                state_machine.next_state_symbolic(&cmd, Var(commands.len()));
+                commands.push(tree.clone());
+            }
+        }
+        let remaining = size.saturating_sub(commands.len());
+        self.generate(runner, &mut state_machine, remaining, &mut commands)?;
+
+        let elements = boxed(&commands);
+        *self.generated.borrow_mut() = commands;
+        Ok(self.base.value_tree(elements))
+    }
+}
+
+/// Run a set of tests for the provided system-under-test, like `run`, but instead of
+/// generating independent command sequences, search for the command sequences which
+/// maximize the utility reported through `target`. Each command sequence is a
+/// neighbour of the best one found so far, which helps reaching rare states of the
+/// system. The search is a simulated annealing, whose initial temperature is set
+/// through `Config::targeted_temperature`.
+pub fn run_targeted<SM, SUTF>(
+    config: Config,
+    state_machine: SM,
+    system_under_test_factory: SUTF,
+) -> std::result::Result<(), TestFailure<CommandSequence<SM>>>
+where
+    SM: StateMachine + Clone + Debug,
+    SM::Command: 'static,
+    SUTF: Fn() -> Box<dyn SystemUnderTest<SM::Command, SM::CommandResult>>,
+{
+    let mut runner = TestRunner::new(config.proptest.clone());
+    let strategy = TargetedStrategy::new(&config, state_machine);
+
+    let result = runner.run(&strategy, |mut commands| {
+        let mut sys = system_under_test_factory();
+        let (result, utility) = collect_utility(|| commands.run(&mut sys));
+        result?;
+        strategy.evaluated(utility);
+        Ok(())
+    });
+    report_failure(result, strategy.base.generation_error.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::strategy::{BoxedStrategy, Strategy, ValueTree};
+    use proptest::test_runner::TestRunner;
+
+    use super::TargetedStrategy;
+    use crate::test_models::{StackCommand, StackModel, StackSystem};
+    use crate::{run_targeted, Config, Result, StateMachine, TestFailure};
+
+    // The stack overflows once it holds 15 elements, which random walks rarely reach
+    #[test]
+    fn targeted_search_reaches_rare_states() {
+        let mut config = Config {
+            min_sequence_size: 20,
+            max_sequence_size: 20,
+            targeted_temperature: 0.0,
+            ..Config::default()
+        };
+        config.proptest.max_shrink_iters = 1000;
+        let result = run_targeted(config, StackModel { depth: 0 }, || {
+            Box::new(StackSystem {
+                depth: 0,
+                capacity: 14,
+            })
+        });
+        match result {
+            Err(TestFailure::Fail(_, seq)) => {
+                assert_eq!(seq.commands, vec![StackCommand::Push; 15])
+            }
+            _ => panic!("Test should have failed"),
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct AdderModel;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Add(usize);
+
+    impl StateMachine for AdderModel {
+        type Command = Add;
+
+        type CommandResult = ();
+
+        fn reset(&mut self) {}
+
+        fn commands(&self) -> Vec<(usize, BoxedStrategy<Self::Command>)> {
+            vec![(1, (0..1000usize).prop_map(Add).boxed())]
+        }
+
+        fn postcondition(&self, _cmd: &Self::Command, _res: &Self::CommandResult) -> Result<()> {
+            Ok(())
+        }
+
+        fn next_state(&mut self, _cmd: &Self::Command) {}
+    }
+
+    #[test]
+    fn neighbours_simplify_commands_of_accepted_sequence() {
+        let config = Config {
+            min_sequence_size: 5,
+            max_sequence_size: 5,
+            shrink_commands: true,
+            min_shrink_size: 5,
+            ..Config::default()
+        };
+        let strategy = TargetedStrategy::new(&config, AdderModel);
+        let mut runner = TestRunner::default();
+        strategy.new_tree(&mut runner).unwrap();
+        strategy.evaluated(0.0);
+
+        // Shrink the neighbour as if it failed: the commands copied from the accepted
+        // command sequence are simplified along with the new one
+        let mut tree = strategy.new_tree(&mut runner).unwrap();
+        while tree.simplify() {}
+        assert_eq!(tree.current().commands, vec![Add(0); 5]);
+    }
+}
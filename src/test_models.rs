@@ -0,0 +1,153 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Models and systems-under-test shared by the unit tests of the crate
+
+use proptest::strategy::{BoxedStrategy, Just, Strategy};
+
+use crate::{target, Error, Result, StateMachine, SystemUnderTest};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum StackCommand {
+    Push,
+    Pop,
+}
+
+/// Model of a stack which only tracks its depth. The depth is reported as the utility
+/// of the command sequence to `run_targeted`.
+#[derive(Clone, Debug)]
+pub(crate) struct StackModel {
+    pub(crate) depth: usize,
+}
+
+impl StateMachine for StackModel {
+    type Command = StackCommand;
+
+    type CommandResult = usize;
+
+    fn reset(&mut self) {
+        self.depth = 0;
+    }
+
+    fn commands(&self) -> Vec<(usize, BoxedStrategy<Self::Command>)> {
+        vec![
+            (1, Just(StackCommand::Push).boxed()),
+            (1, Just(StackCommand::Pop).boxed()),
+        ]
+    }
+
+    fn precondition(&self, cmd: &Self::Command) -> bool {
+        *cmd == StackCommand::Push || self.depth > 0
+    }
+
+    fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+        let expected = match *cmd {
+            StackCommand::Push => self.depth + 1,
+            StackCommand::Pop => self.depth - 1,
+        };
+        if *res != expected {
+            return Err(Error::postcondition(
+                format!("{:?}", cmd),
+                format!("{:?}", expected),
+                format!("{:?}", res),
+            ));
+        }
+        Ok(())
+    }
+
+    fn next_state(&mut self, cmd: &Self::Command) {
+        match *cmd {
+            StackCommand::Push => self.depth += 1,
+            StackCommand::Pop => self.depth -= 1,
+        }
+        target(self.depth as f64);
+    }
+}
+
+/// Stack which only tracks its depth, answering each command with the new depth. Pushes
+/// beyond its capacity fail with a stack overflow.
+pub(crate) struct StackSystem {
+    pub(crate) depth: usize,
+    pub(crate) capacity: usize,
+}
+
+impl SystemUnderTest<StackCommand, usize> for StackSystem {
+    fn run(&mut self, cmd: &StackCommand) -> Result<usize> {
+        match *cmd {
+            StackCommand::Push => {
+                if self.depth == self.capacity {
+                    return Err(Error::system_under_test(std::io::Error::other(
+                        "Stack overflow",
+                    )));
+                }
+                self.depth += 1;
+            }
+            StackCommand::Pop => {
+                if self.depth == 0 {
+                    return Err(Error::system_under_test(std::io::Error::other(
+                        "Stack underflow",
+                    )));
+                }
+                self.depth -= 1;
+            }
+        }
+        Ok(self.depth)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum CounterCommand {
+    Increment,
+}
+
+/// Model of a counter, whose increments return the incremented value
+#[derive(Clone, Debug)]
+pub(crate) struct CounterModel {
+    pub(crate) value: usize,
+}
+
+impl StateMachine for CounterModel {
+    type Command = CounterCommand;
+
+    type CommandResult = usize;
+
+    fn reset(&mut self) {
+        self.value = 0;
+    }
+
+    fn commands(&self) -> Vec<(usize, BoxedStrategy<Self::Command>)> {
+        vec![(1, Just(CounterCommand::Increment).boxed())]
+    }
+
+    fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()> {
+        if *res != self.value + 1 {
+            return Err(Error::postcondition(
+                format!("{:?}", cmd),
+                format!("{:?}", self.value + 1),
+                format!("{:?}", res),
+            ));
+        }
+        Ok(())
+    }
+
+    fn next_state(&mut self, _cmd: &Self::Command) {
+        self.value += 1;
+    }
+}
+
+pub(crate) struct Counter {
+    pub(crate) value: usize,
+}
+
+impl SystemUnderTest<CounterCommand, usize> for Counter {
+    fn run(&mut self, _cmd: &CounterCommand) -> Result<usize> {
+        self.value += 1;
+        Ok(self.value)
+    }
+}
@@ -5,7 +5,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::errors::Result;
+use std::sync::{Mutex, PoisonError};
+
+use crate::errors::{Error, Result};
+use crate::symbolic::{Environment, Var};
 use proptest::strategy::BoxedStrategy;
 
 /// This trait represents the interface to the system-under-test. The two type parameters,
@@ -15,12 +18,63 @@ pub trait SystemUnderTest<C, R> {
     /// The method takes a reference to a system command, applies the command to the system
     /// and updates its internal state, returning the corresponding response.
     fn run(&mut self, cmd: &C) -> Result<R>;
+
+    /// Release the resources held by the system (temporary files, child processes, etc.).
+    /// The method is called once the command sequence has been run, whether it succeeded
+    /// or not.
+    fn cleanup(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Restore the system to its initial state, so that it can be reused by `run_reused`
+    /// for the next command sequence. Systems which cannot be reset keep the default
+    /// implementation, which fails, causing the system to be rebuilt instead.
+    fn reset(&mut self) -> Result<()> {
+        Err(Error::system_under_test(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "The system-under-test cannot be reset",
+        )))
+    }
+}
+
+/// This trait is implemented by systems-under-test which can expose an abstract
+/// observation of their internal state, of type `O`, to be compared with the
+/// observation of the model after each command.
+pub trait ObservableSystem<C, R, O>: SystemUnderTest<C, R> {
+    /// Returns the observation of the current state of the system
+    fn observe(&self) -> Result<O>;
+}
+
+/// This trait represents the interface to a system-under-test which accepts commands from
+/// several threads at the same time. It is used to run the concurrent branches of a
+/// parallel command sequence.
+pub trait ConcurrentSystemUnderTest<C, R>: Sync {
+    /// The method takes a reference to a system command, applies the command to the system,
+    /// possibly concurrently with other commands, and returns the corresponding response.
+    fn run(&self, cmd: &C) -> Result<R>;
+}
+
+/// Serializes the access to a sequential system-under-test, so that each command is
+/// applied atomically
+impl<C, R, T> ConcurrentSystemUnderTest<C, R> for Mutex<T>
+where
+    T: SystemUnderTest<C, R> + Send,
+{
+    fn run(&self, cmd: &C) -> Result<R> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).run(cmd)
+    }
 }
 
 /// The trait defines the interface of the simplified model of the system-under-test.
 pub trait StateMachine {
-    /// Type which encodes the commands accepted by the model
-    type Command: std::fmt::Debug;
+    /// Type which encodes the commands accepted by the model. Commands must be `Clone`,
+    /// since the library copies them: the default implementation of `resolve` returns
+    /// a copy of the command run against the system-under-test, and shrinking keeps
+    /// the candidates offered by `shrink_command` and explores the neighbours of a
+    /// command sequence (`run_targeted`, `run_exhaustive`) by copying commands.
+    /// Commands which hold resources that cannot be copied, such as file handles,
+    /// should hold a description of the resource instead.
+    type Command: Clone + std::fmt::Debug;
 
     /// Type which encodes the responses of the model to the various commands
     type CommandResult: std::fmt::Debug;
@@ -35,10 +89,130 @@ pub trait StateMachine {
     /// to bias writes over reads).
     fn commands(&self) -> Vec<(usize, BoxedStrategy<Self::Command>)>;
 
+    /// Returns every command which may be applied to the current state of the model,
+    /// for the exhaustive enumeration of command sequences done by `run_exhaustive`.
+    /// The default implementation returns `None`, in which case the commands are
+    /// enumerated by sampling the strategies returned by `commands` until their finite
+    /// domain is exhausted.
+    fn enumerate_commands(&self) -> Option<Vec<Self::Command>> {
+        None
+    }
+
+    /// Returns the name of the kind of the provided command, under which the command is
+    /// counted in the statistics of a test run. The default implementation returns the
+    /// leading identifier of the `Debug` representation, which is the variant name for
+    /// commands encoded as enums.
+    fn command_name(&self, cmd: &Self::Command) -> String {
+        format!("{:?}", cmd)
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect()
+    }
+
+    /// Check whether the provided command can be applied to the current state of the
+    /// system model. Commands which do not satisfy their precondition are discarded
+    /// during generation and skipped when replaying a shrunk command sequence.
+    /// The default implementation accepts every command.
+    fn precondition(&self, _cmd: &Self::Command) -> bool {
+        true
+    }
+
+    /// Check whether the model has reached an end state, such as a closed connection,
+    /// after which no command can be applied. Generation ends the command sequence as
+    /// soon as the model is in a terminal state, and commands following a terminal
+    /// state are skipped when replaying a shrunk command sequence. The default
+    /// implementation never terminates.
+    fn is_terminal(&self) -> bool {
+        false
+    }
+
     /// Check that all postconditions would hold after applying the provided command to
     /// the current state of the system model
     fn postcondition(&self, cmd: &Self::Command, res: &Self::CommandResult) -> Result<()>;
 
+    /// Check that the invariants of the model hold in its current state. The method is
+    /// called after every state transition, both when generating and when running a
+    /// command sequence. The default implementation accepts every state.
+    fn invariant(&self) -> Result<()> {
+        Ok(())
+    }
+
     /// Advance the system model to the next state by applying the provided command
     fn next_state(&mut self, cmd: &Self::Command);
+
+    /// Advance the system model to the next state by applying the provided command,
+    /// whose result is bound to the symbolic variable `var`. Models which let later
+    /// commands refer to the results of earlier ones should record the variable here.
+    /// The default implementation ignores the variable and calls `next_state`.
+    fn next_state_symbolic(&mut self, cmd: &Self::Command, _var: Var) {
+        self.next_state(cmd);
+    }
+
+    /// Returns the symbolic variables referenced by the provided command. When shrinking,
+    /// commands referencing variables whose defining commands were removed are dropped
+    /// from the sequence. The default implementation returns no variables.
+    fn variables(&self, _cmd: &Self::Command) -> Vec<Var> {
+        Vec::new()
+    }
+
+    /// Returns simpler commands which may replace the provided command when shrinking
+    /// a failing command sequence, for example a cheaper kind of operation. The model
+    /// is in the state preceding the command. Candidates are tried in order and are
+    /// only kept if every command of the sequence remains applicable; they should be
+    /// strictly simpler than the command, so that shrinking terminates. The default
+    /// implementation offers no candidates.
+    fn shrink_command(&self, _cmd: &Self::Command) -> Vec<Self::Command> {
+        Vec::new()
+    }
+
+    /// Returns a copy of the provided command in which every symbolic variable is
+    /// replaced by its concrete value from the environment. The resolved command is
+    /// the one passed to the system-under-test. The default implementation returns
+    /// the command unchanged.
+    fn resolve(
+        &self,
+        cmd: &Self::Command,
+        _env: &Environment<Self::CommandResult>,
+    ) -> Result<Self::Command> {
+        Ok(cmd.clone())
+    }
+}
+
+/// Bound on the commands of the models tested by `run`. With the `serde` feature, the
+/// minimal failing command sequences are stored in the regressions file
+/// (`Config::regressions_file`), so the commands must be serializable with serde.
+/// Without the feature, every command type satisfies the bound.
+#[cfg(feature = "serde")]
+pub trait PersistentCommand: serde::Serialize + serde::de::DeserializeOwned {}
+
+#[cfg(feature = "serde")]
+impl<T> PersistentCommand for T where T: serde::Serialize + serde::de::DeserializeOwned {}
+
+/// Bound on the commands of the models tested by `run`, which every command type
+/// satisfies without the `serde` feature
+#[cfg(not(feature = "serde"))]
+pub trait PersistentCommand {}
+
+#[cfg(not(feature = "serde"))]
+impl<T> PersistentCommand for T {}
+
+/// The trait is implemented by command types which know how to generate themselves
+/// from the current state of the model `M`. It is usually derived with
+/// `#[derive(Commands)]`, which requires the `derive` feature, and called from
+/// `StateMachine::commands`.
+pub trait Commands<M>: Sized {
+    /// Returns the weighted strategies for the commands which are enabled in the
+    /// provided state of the model, in the format expected by `StateMachine::commands`
+    fn strategies(model: &M) -> Vec<(usize, BoxedStrategy<Self>)>;
+}
+
+/// The trait extends the model with an abstract observation of its state, which is
+/// compared with the observation of an `ObservableSystem` after each command.
+pub trait ObservableStateMachine: StateMachine {
+    /// Type which encodes the observation of the state of the system
+    type Observation: PartialEq + std::fmt::Debug;
+
+    /// Returns the observation expected from the system-under-test in the current
+    /// state of the model
+    fn observe(&self) -> Self::Observation;
 }
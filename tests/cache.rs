@@ -48,7 +48,7 @@ impl Cache {
     pub fn set(&mut self, key: isize, val: isize) -> Result<()> {
         let tx = self.conn.transaction()?;
         let params = &[(":key", &key), (":val", &val)];
-        let _ = match tx
+        match tx
             .query_row("select val from cache where key = ?", [key], |row| {
                 row.get::<_, isize>(0)
             })
@@ -78,6 +78,14 @@ impl Cache {
         tx.commit()
     }
 
+    pub fn entries(&self) -> Result<Vec<(isize, isize)>> {
+        let mut stmt = self
+            .conn
+            .prepare("select key, val from cache order by key")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         self.conn.execute("delete from cache", [])?;
         Ok(())
@@ -91,9 +99,13 @@ mod tests {
     use super::Cache;
 
     use proptest::prelude::*;
-    use proptest_stateful::{run, Config, Error, Result, StateMachine, SystemUnderTest};
+    use proptest_stateful::{
+        label, run_observed, state_machine_test, Config, Error, ObservableStateMachine,
+        ObservableSystem, Result, StateMachine, SystemUnderTest,
+    };
 
     #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     enum CacheCommand {
         Get { key: isize },
         Set { key: isize, value: isize },
@@ -108,27 +120,32 @@ mod tests {
 
     impl SystemUnderTest<CacheCommand, CommandResult> for Cache {
         fn run(&mut self, cmd: &CacheCommand) -> Result<CommandResult> {
-            match cmd {
-                &CacheCommand::Get { key } => {
-                    let v = self.get(key).map_err(|e| Error::system_under_test(e))?;
+            match *cmd {
+                CacheCommand::Get { key } => {
+                    let v = self.get(key).map_err(Error::system_under_test)?;
                     match v {
                         Some(v) => Ok(CommandResult::Some(v)),
                         None => Ok(CommandResult::None),
                     }
                 }
-                &CacheCommand::Set { key, value } => {
-                    self.set(key, value)
-                        .map_err(|e| Error::system_under_test(e))?;
+                CacheCommand::Set { key, value } => {
+                    self.set(key, value).map_err(Error::system_under_test)?;
                     Ok(CommandResult::None)
                 }
-                &CacheCommand::Flush => {
-                    self.flush().map_err(|e| Error::system_under_test(e))?;
+                CacheCommand::Flush => {
+                    self.flush().map_err(Error::system_under_test)?;
                     Ok(CommandResult::None)
                 }
             }
         }
     }
 
+    impl ObservableSystem<CacheCommand, CommandResult, Vec<(isize, isize)>> for Cache {
+        fn observe(&self) -> Result<Vec<(isize, isize)>> {
+            self.entries().map_err(Error::system_under_test)
+        }
+    }
+
     #[derive(Clone, Debug)]
     struct Entry {
         index: usize,
@@ -195,7 +212,7 @@ mod tests {
 
         fn postcondition(&self, cmd: &CacheCommand, res: &CommandResult) -> Result<()> {
             if let CacheCommand::Get { key } = cmd {
-                match self.entries.get(&key) {
+                match self.entries.get(key) {
                     Some(Entry { val, .. }) => {
                         if res != &CommandResult::Some(*val) {
                             return Result::Err(Error::postcondition(
@@ -219,10 +236,21 @@ mod tests {
             Ok(())
         }
 
+        fn invariant(&self) -> Result<()> {
+            if self.entries.len() > self.max_num_entries {
+                return Err(Error::invariant(format!(
+                    "Cache holds {} entries, more than the maximum of {}",
+                    self.entries.len(),
+                    self.max_num_entries
+                )));
+            }
+            Ok(())
+        }
+
         fn next_state(&mut self, cmd: &CacheCommand) {
-            match cmd {
-                &CacheCommand::Get { key: _ } => {}
-                &CacheCommand::Set { key, value } => {
+            match *cmd {
+                CacheCommand::Get { key: _ } => {}
+                CacheCommand::Set { key, value } => {
                     if let Some(entry) = self.entries.get_mut(&key) {
                         entry.val = value;
                     } else {
@@ -235,6 +263,7 @@ mod tests {
                                 .collect::<Vec<isize>>()[0];
                             self.entries.remove(&key_to_delete);
                             self.min_index += 1;
+                            label("eviction");
                         }
                         self.entries.insert(
                             key,
@@ -246,7 +275,8 @@ mod tests {
                         self.max_index += 1;
                     }
                 }
-                &CacheCommand::Flush => {
+                CacheCommand::Flush => {
+                    label("flush");
                     self.min_index = 0;
                     self.max_index = 0;
                     self.entries.clear();
@@ -255,15 +285,69 @@ mod tests {
         }
     }
 
+    impl ObservableStateMachine for CacheModel {
+        type Observation = Vec<(isize, isize)>;
+
+        fn observe(&self) -> Self::Observation {
+            let mut entries = self
+                .entries
+                .iter()
+                .map(|(k, e)| (*k, e.val))
+                .collect::<Vec<_>>();
+            entries.sort_unstable();
+            entries
+        }
+    }
+
+    const MAX_CACHE_SIZE: usize = 10;
+
+    state_machine_test! {
+        #[test]
+        fn cache() {
+            model: CacheModel::new(MAX_CACHE_SIZE),
+            system: Cache::new(MAX_CACHE_SIZE).expect("Could not construct Cache"),
+            config: {
+                let mut config = Config {
+                    print_statistics: true,
+                    ..Config::default()
+                };
+                config.proptest.max_shrink_iters = 100;
+                config
+            },
+        }
+
+        #[test]
+        fn cache_observed() {
+            model: CacheModel::new(MAX_CACHE_SIZE),
+            system: Cache::new(MAX_CACHE_SIZE).expect("Could not construct Cache"),
+            config: {
+                let mut config = Config::default();
+                config.proptest.max_shrink_iters = 100;
+                config
+            },
+            runner: run_observed,
+        }
+    }
+
+    // Generated with `CommandSequence::to_regression_test`
     #[test]
-    fn cache() {
-        const MAX_CACHE_SIZE: usize = 10;
-        let mut config = Config::default();
-        config.proptest.max_shrink_iters = 100;
-        config.proptest.source_file = Some("tests/cache.rs");
-        let result = run(config, CacheModel::new(MAX_CACHE_SIZE), || {
-            Box::new(Cache::new(MAX_CACHE_SIZE).expect("Could not construct Cache"))
-        });
-        assert!(result.is_ok());
+    fn cache_regression() {
+        let mut commands = proptest_stateful::CommandSequence::new(
+            CacheModel::new(1),
+            vec![
+                (
+                    proptest_stateful::Var(0),
+                    CacheCommand::Set { key: 1, value: 2 },
+                ),
+                (
+                    proptest_stateful::Var(1),
+                    CacheCommand::Set { key: 3, value: 4 },
+                ),
+                (proptest_stateful::Var(2), CacheCommand::Get { key: 1 }),
+            ],
+        );
+        let mut sys: Box<dyn proptest_stateful::SystemUnderTest<_, _>> =
+            Box::new(Cache::new(1).unwrap());
+        commands.run(&mut sys).unwrap_or_else(|e| panic!("{}", e));
     }
 }
@@ -0,0 +1,150 @@
+//
+// Copyright 2021 Radu Popescu <mail@radupopescu.net>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/// Bounded stack whose model generates its commands with `#[derive(Commands)]`
+struct Stack {
+    values: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stack;
+
+    use proptest::prelude::*;
+    use proptest::test_runner::TestRunner;
+    use proptest_stateful::{run, Commands, Config, Error, Result, StateMachine, SystemUnderTest};
+
+    #[derive(Clone, Debug, PartialEq, Commands)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[commands(model = StackModel)]
+    enum StackCommand {
+        #[weight(3)]
+        #[enabled_if(model.values.len() < model.capacity)]
+        Push {
+            #[strategy(0..model.capacity as u8)]
+            value: u8,
+        },
+        #[enabled_if(!model.values.is_empty())]
+        Pop,
+        Peek(#[strategy(Just(true))] bool),
+        #[weight(0)]
+        Clear,
+    }
+
+    impl SystemUnderTest<StackCommand, Option<u8>> for Stack {
+        fn run(&mut self, cmd: &StackCommand) -> Result<Option<u8>> {
+            match *cmd {
+                StackCommand::Push { value } => {
+                    self.values.push(value);
+                    Ok(None)
+                }
+                StackCommand::Pop => Ok(self.values.pop()),
+                StackCommand::Peek(_) => Ok(self.values.last().copied()),
+                StackCommand::Clear => {
+                    self.values.clear();
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct StackModel {
+        values: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl StateMachine for StackModel {
+        type Command = StackCommand;
+        type CommandResult = Option<u8>;
+
+        fn reset(&mut self) {
+            self.values.clear();
+        }
+
+        fn commands(&self) -> Vec<(usize, BoxedStrategy<StackCommand>)> {
+            StackCommand::strategies(self)
+        }
+
+        fn postcondition(&self, cmd: &StackCommand, res: &Option<u8>) -> Result<()> {
+            let expected = match *cmd {
+                StackCommand::Pop | StackCommand::Peek(_) => self.values.last().copied(),
+                _ => None,
+            };
+            if *res != expected {
+                return Err(Error::postcondition(
+                    format!("{:?}", cmd),
+                    format!("{:?}", expected),
+                    format!("{:?}", res),
+                ));
+            }
+            Ok(())
+        }
+
+        fn invariant(&self) -> Result<()> {
+            if self.values.len() > self.capacity {
+                return Err(Error::invariant("Stack exceeds its capacity"));
+            }
+            Ok(())
+        }
+
+        fn next_state(&mut self, cmd: &StackCommand) {
+            match *cmd {
+                StackCommand::Push { value } => self.values.push(value),
+                StackCommand::Pop => {
+                    self.values.pop();
+                }
+                StackCommand::Peek(_) => {}
+                StackCommand::Clear => self.values.clear(),
+            }
+        }
+    }
+
+    #[test]
+    fn derived_strategies_follow_attributes() {
+        let mut runner = TestRunner::deterministic();
+        let sample = |model: &StackModel, runner: &mut TestRunner| {
+            StackCommand::strategies(model)
+                .into_iter()
+                .map(|(weight, strategy)| (weight, strategy.new_tree(runner).unwrap().current()))
+                .collect::<Vec<_>>()
+        };
+
+        let empty = StackModel {
+            values: vec![],
+            capacity: 1,
+        };
+        assert_eq!(
+            sample(&empty, &mut runner),
+            vec![
+                (3, StackCommand::Push { value: 0 }),
+                (1, StackCommand::Peek(true))
+            ]
+        );
+
+        let full = StackModel {
+            values: vec![0],
+            capacity: 1,
+        };
+        assert_eq!(
+            sample(&full, &mut runner),
+            vec![(1, StackCommand::Pop), (1, StackCommand::Peek(true))]
+        );
+    }
+
+    #[test]
+    fn derived_strategies_drive_test_run() {
+        let model = StackModel {
+            values: vec![],
+            capacity: 4,
+        };
+        let result = run(Config::default(), model, || {
+            Box::new(Stack { values: vec![] })
+        });
+        assert!(result.is_ok());
+    }
+}